@@ -0,0 +1,272 @@
+//! Retry and reconnect state machine of a capture device which can be lost, e.g. unplugged.
+//!
+//! The [`Supervisor`] doesn't know the device, so it can be shared between crates built against
+//! different versions of kornia. The caller opens, grabs and closes the device, reports the
+//! outcome, and logs the returned [`Status`] messages.
+
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// Parameters of the [`Supervisor`].
+#[derive(Debug, Clone, Copy)]
+pub struct SupervisorConfig {
+    /// Consecutive failed grabs which are retried before the device is reopened
+    pub max_retries: u32,
+    /// Time without a frame after which the device is considered unplugged
+    pub stall_timeout: Duration,
+    /// Delay before reopening the device, doubled after every failed attempt to open it and
+    /// every time it's lost before it delivered a frame again
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            stall_timeout: Duration::from_secs(5),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+/// Change of the status of the device, which should be logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub level: StatusLevel,
+    pub message: String,
+}
+
+impl Status {
+    fn new(level: StatusLevel, message: String) -> Self {
+        Self { level, message }
+    }
+}
+
+/// Outcome of a reported grab.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transition {
+    /// Nothing changed
+    None,
+    /// The device is kept, but the status changed
+    Status(Status),
+    /// The device is lost, it must be closed and is reopened after the backoff
+    Lost(Status),
+}
+
+/// Decides when a device is retried, closed and reopened. Failed grabs are retried, and after
+/// too many of them, or when no frame arrives for a while, the device is lost and reopened with
+/// an exponential backoff.
+#[derive(Debug)]
+pub struct Supervisor {
+    config: SupervisorConfig,
+    consecutive_errors: u32,
+    last_frame: Instant,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl Supervisor {
+    pub fn new(config: SupervisorConfig) -> Self {
+        Self {
+            config,
+            consecutive_errors: 0,
+            last_frame: Instant::now(),
+            backoff: config.initial_backoff,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Time left before the device may be opened, zero once the backoff has passed.
+    pub fn open_delay(&self) -> Duration {
+        self.next_attempt.saturating_duration_since(Instant::now())
+    }
+
+    /// Reports that the device was opened.
+    pub fn opened(&mut self) -> Status {
+        self.consecutive_errors = 0;
+        self.last_frame = Instant::now();
+
+        Status::new(StatusLevel::Info, "Opened".to_string())
+    }
+
+    /// Reports that the device couldn't be opened, it's retried after the backoff.
+    pub fn open_failed(&mut self, err: impl Display) -> Status {
+        let status = Status::new(
+            StatusLevel::Warn,
+            format!(
+                "Failed to open: {err}, retrying in {:.1} s",
+                self.backoff.as_secs_f32()
+            ),
+        );
+        self.delay_next_attempt();
+
+        status
+    }
+
+    /// Reports a grabbed frame.
+    pub fn frame(&mut self) -> Transition {
+        let transition = if self.consecutive_errors > 0 {
+            Transition::Status(Status::new(
+                StatusLevel::Info,
+                format!("Recovered after {} failed grabs", self.consecutive_errors),
+            ))
+        } else {
+            Transition::None
+        };
+
+        self.consecutive_errors = 0;
+        self.last_frame = Instant::now();
+        // Only frames flowing again show the device is back, opening it doesn't
+        self.backoff = self.config.initial_backoff;
+
+        transition
+    }
+
+    /// Reports a grab without a frame, the device is lost once it stalled for too long.
+    pub fn no_frame(&mut self) -> Transition {
+        let elapsed = self.last_frame.elapsed();
+        if elapsed <= self.config.stall_timeout {
+            return Transition::None;
+        }
+
+        self.lost(format!("No frame for {:.1} s", elapsed.as_secs_f32()))
+    }
+
+    /// Reports a failed grab, the device is lost after too many of them in a row.
+    pub fn grab_failed(&mut self, err: impl Display) -> Transition {
+        self.consecutive_errors += 1;
+
+        if self.consecutive_errors > self.config.max_retries {
+            return self.lost(format!(
+                "{} consecutive failed grabs, last error: {err}",
+                self.consecutive_errors
+            ));
+        }
+
+        Transition::Status(Status::new(
+            StatusLevel::Warn,
+            format!(
+                "Failed to grab a frame ({}/{}): {err}",
+                self.consecutive_errors, self.config.max_retries
+            ),
+        ))
+    }
+
+    fn lost(&mut self, reason: String) -> Transition {
+        let status = Status::new(
+            StatusLevel::Error,
+            format!("{reason}, reopening in {:.1} s", self.backoff.as_secs_f32()),
+        );
+        // A device which opens but stalls again waits longer every time
+        self.delay_next_attempt();
+
+        Transition::Lost(status)
+    }
+
+    fn delay_next_attempt(&mut self) {
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(self.config.max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SupervisorConfig {
+        SupervisorConfig {
+            max_retries: 2,
+            stall_timeout: Duration::from_secs(60),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3),
+        }
+    }
+
+    fn lost_message(transition: Transition) -> String {
+        match transition {
+            Transition::Lost(status) => status.message,
+            transition => panic!("Expected the device to be lost, got {transition:?}"),
+        }
+    }
+
+    #[test]
+    fn failed_grabs_are_retried_before_the_device_is_lost() {
+        let mut supervisor = Supervisor::new(config());
+        supervisor.opened();
+
+        for retry in 1..=2 {
+            let Transition::Status(status) = supervisor.grab_failed("timeout") else {
+                panic!("Failed grab {retry} should be retried");
+            };
+            assert_eq!(status.level, StatusLevel::Warn);
+            assert_eq!(
+                status.message,
+                format!("Failed to grab a frame ({retry}/2): timeout")
+            );
+        }
+
+        assert_eq!(
+            lost_message(supervisor.grab_failed("timeout")),
+            "3 consecutive failed grabs, last error: timeout, reopening in 1.0 s"
+        );
+        assert!(supervisor.open_delay() > Duration::ZERO);
+    }
+
+    #[test]
+    fn frames_report_the_recovery_once() {
+        let mut supervisor = Supervisor::new(config());
+        supervisor.opened();
+        supervisor.grab_failed("timeout");
+
+        assert_eq!(
+            supervisor.frame(),
+            Transition::Status(Status::new(
+                StatusLevel::Info,
+                "Recovered after 1 failed grabs".to_string()
+            ))
+        );
+        assert_eq!(supervisor.frame(), Transition::None);
+        assert_eq!(supervisor.no_frame(), Transition::None);
+    }
+
+    #[test]
+    fn backoff_grows_until_frames_flow_again() {
+        let mut supervisor = Supervisor::new(config());
+
+        assert_eq!(
+            supervisor.open_failed("busy").message,
+            "Failed to open: busy, retrying in 1.0 s"
+        );
+
+        // Opening doesn't reset the backoff, a device which is lost again waits longer
+        supervisor.opened();
+        for _ in 0..2 {
+            supervisor.grab_failed("timeout");
+        }
+        assert!(lost_message(supervisor.grab_failed("timeout")).ends_with("reopening in 2.0 s"));
+
+        supervisor.opened();
+        for _ in 0..2 {
+            supervisor.grab_failed("timeout");
+        }
+        assert!(lost_message(supervisor.grab_failed("timeout")).ends_with("reopening in 3.0 s"));
+
+        supervisor.opened();
+        supervisor.frame();
+        assert_eq!(
+            supervisor.open_failed("busy").message,
+            "Failed to open: busy, retrying in 1.0 s"
+        );
+    }
+}
@@ -1,17 +1,25 @@
 use argh::FromArgs;
-use kornia::{image::ImageSize, imgproc::draw::draw_line, io::stream::V4L2CameraConfig};
+use device_supervisor::{Status, StatusLevel, Supervisor, SupervisorConfig, Transition};
+use kornia::{
+    image::ImageSize,
+    imgproc::draw::draw_line,
+    io::stream::{StreamCapture, V4L2CameraConfig},
+};
 use rand::{Rng, SeedableRng};
-use std::sync::{
-    Arc,
-    atomic::{AtomicBool, Ordering},
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(FromArgs)]
 /// Capture frames from a webcam and detect april tag while logging to rerun.
 struct Args {
-    /// the camera id to use
-    #[argh(option, short = 'c', default = "0")]
-    camera_id: u32,
+    /// the camera id to use, can be repeated to capture several cameras in parallel (default: 0)
+    #[argh(option, short = 'c')]
+    camera_id: Vec<u32>,
 
     /// the frames per second to record
     #[argh(option, short = 'f', default = "30")]
@@ -23,17 +31,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     let rec = rerun::RecordingStreamBuilder::new("Kornia_aprilgrid").connect_grpc()?;
 
-    // Create a wecam object
-    let mut webcam = V4L2CameraConfig::new()
-        .with_camera_id(args.camera_id)
-        .with_fps(args.fps)
-        .with_size(ImageSize {
-            width: 640,
-            height: 480,
-        })
-        .build()?;
-
-    webcam.start()?;
+    let camera_ids = if args.camera_id.is_empty() {
+        vec![0]
+    } else {
+        args.camera_id.clone()
+    };
 
     // create a cancel token to stop the webcam capture
     let cancel_token = Arc::new(AtomicBool::new(false));
@@ -46,59 +48,220 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     })?;
 
+    // Every camera is captured and detected on its own thread, logging under its own entity path
+    let errors = std::thread::scope(|scope| {
+        let handles = camera_ids
+            .iter()
+            .map(|&camera_id| {
+                let rec = rec.clone();
+                let cancel_token = &cancel_token;
+                let fps = args.fps;
+
+                // Keep the entity paths of a single camera as they were
+                let prefix = if camera_ids.len() > 1 {
+                    format!("camera_{camera_id}/")
+                } else {
+                    String::new()
+                };
+
+                scope.spawn(move || {
+                    run_camera(&rec, camera_id, fps, &prefix, cancel_token)
+                        .map_err(|err| format!("camera {camera_id}: {err}"))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .filter_map(|handle| match handle.join() {
+                Ok(result) => result.err(),
+                Err(_) => Some("Camera thread panicked".to_string()),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+
+    Ok(())
+}
+
+/// Camera which is closed when dropped, so it's released on every exit path.
+struct Camera(StreamCapture);
+
+impl Drop for Camera {
+    fn drop(&mut self) {
+        // NOTE: this is important to close the webcam properly, otherwise the app will hang
+        if let Err(err) = self.0.close() {
+            eprintln!("Failed to close the camera: {err}");
+        }
+    }
+}
+
+fn open_camera(camera_id: u32, fps: u32) -> Result<Camera, Box<dyn std::error::Error>> {
+    // Create a wecam object
+    let mut webcam = Camera(
+        V4L2CameraConfig::new()
+            .with_camera_id(camera_id)
+            .with_fps(fps)
+            .with_size(ImageSize {
+                width: 640,
+                height: 480,
+            })
+            .build()?,
+    );
+
+    webcam.0.start()?;
+
+    Ok(webcam)
+}
+
+fn log_status(
+    rec: &rerun::RecordingStream,
+    path: &str,
+    status: Status,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let level = match status.level {
+        StatusLevel::Info => rerun::TextLogLevel::INFO,
+        StatusLevel::Warn => rerun::TextLogLevel::WARN,
+        StatusLevel::Error => rerun::TextLogLevel::ERROR,
+    };
+
+    println!("{path}: {}", status.message);
+    rec.log(path, &rerun::TextLog::new(status.message).with_level(level))?;
+    Ok(())
+}
+
+/// Captures the camera and logs the detected tags until the cancel token is set. Failed grabs are
+/// retried, and the camera is reopened with a backoff when it's lost, e.g. after an unplug, as
+/// decided by the [`Supervisor`].
+fn run_camera(
+    rec: &rerun::RecordingStream,
+    camera_id: u32,
+    fps: u32,
+    prefix: &str,
+    cancel_token: &AtomicBool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image_path = format!("{prefix}live_camera");
+    let status_path = format!("{prefix}camera_status");
+
     let detector = aprilgrid::detector::TagDetector::new(&aprilgrid::TagFamily::T36H11, None);
+    let mut frame_idx = 0i64;
+    let mut fps_start = Instant::now();
+    let mut fps_frames = 0u32;
+
+    let mut camera = None;
+    let mut supervisor = Supervisor::new(SupervisorConfig::default());
 
     while !cancel_token.load(Ordering::SeqCst) {
-        let Some(mut img) = webcam.grab()? else {
+        let Some(webcam) = camera.as_mut() else {
+            let delay = supervisor.open_delay();
+            if !delay.is_zero() {
+                // Sleep in short steps, so the cancel token keeps being checked
+                std::thread::sleep(delay.min(Duration::from_millis(50)));
+                continue;
+            }
+
+            let status = match open_camera(camera_id, fps) {
+                Ok(webcam) => {
+                    camera = Some(webcam);
+                    supervisor.opened()
+                }
+                Err(err) => supervisor.open_failed(err),
+            };
+            log_status(rec, &status_path, status)?;
             continue;
         };
 
-        let tags = detector.detect_kornia(&img);
-        for (tag_id, corners) in tags {
-            if corners.len() == 4 {
-                // Draw lines connecting the corners to form a quadrilateral
-                let line_color = id_to_color(tag_id);
-                const LINE_THICKNESS: usize = 2;
-
-                draw_line(
-                    &mut img,
-                    (corners[0].0 as i64, corners[0].1 as i64),
-                    (corners[1].0 as i64, corners[1].1 as i64),
-                    line_color,
-                    LINE_THICKNESS,
-                );
-                draw_line(
-                    &mut img,
-                    (corners[1].0 as i64, corners[1].1 as i64),
-                    (corners[2].0 as i64, corners[2].1 as i64),
-                    line_color,
-                    LINE_THICKNESS,
-                );
-                draw_line(
-                    &mut img,
-                    (corners[2].0 as i64, corners[2].1 as i64),
-                    (corners[3].0 as i64, corners[3].1 as i64),
-                    line_color,
-                    LINE_THICKNESS,
-                );
-                draw_line(
-                    &mut img,
-                    (corners[3].0 as i64, corners[3].1 as i64),
-                    (corners[0].0 as i64, corners[0].1 as i64),
-                    line_color,
-                    LINE_THICKNESS,
+        let transition = match webcam.0.grab() {
+            Ok(Some(mut img)) => {
+                let transition = supervisor.frame();
+
+                // Log every frame on its own point of the timelines, so the viewer can scrub
+                // through history
+                rec.set_time_sequence("frame", frame_idx);
+                rec.set_timestamp_secs_since_epoch(
+                    "capture_time",
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs_f64(),
                 );
+                frame_idx += 1;
+
+                let tags = detector.detect_kornia(&img);
+                for (tag_id, corners) in tags {
+                    if corners.len() == 4 {
+                        // Draw lines connecting the corners to form a quadrilateral
+                        let line_color = id_to_color(tag_id);
+                        const LINE_THICKNESS: usize = 2;
+
+                        draw_line(
+                            &mut img,
+                            (corners[0].0 as i64, corners[0].1 as i64),
+                            (corners[1].0 as i64, corners[1].1 as i64),
+                            line_color,
+                            LINE_THICKNESS,
+                        );
+                        draw_line(
+                            &mut img,
+                            (corners[1].0 as i64, corners[1].1 as i64),
+                            (corners[2].0 as i64, corners[2].1 as i64),
+                            line_color,
+                            LINE_THICKNESS,
+                        );
+                        draw_line(
+                            &mut img,
+                            (corners[2].0 as i64, corners[2].1 as i64),
+                            (corners[3].0 as i64, corners[3].1 as i64),
+                            line_color,
+                            LINE_THICKNESS,
+                        );
+                        draw_line(
+                            &mut img,
+                            (corners[3].0 as i64, corners[3].1 as i64),
+                            (corners[0].0 as i64, corners[0].1 as i64),
+                            line_color,
+                            LINE_THICKNESS,
+                        );
+                    }
+                }
+
+                rec.log(
+                    &image_path,
+                    &rerun::Image::from_elements(
+                        img.as_slice(),
+                        img.size().into(),
+                        rerun::ColorModel::RGB,
+                    ),
+                )?;
+
+                fps_frames += 1;
+                let elapsed = fps_start.elapsed().as_secs_f64();
+                if elapsed >= 1.0 {
+                    println!("camera {camera_id}: {:.1} fps", fps_frames as f64 / elapsed);
+                    fps_start = Instant::now();
+                    fps_frames = 0;
+                }
+
+                transition
             }
-        }
+            Ok(None) => supervisor.no_frame(),
+            Err(err) => supervisor.grab_failed(err),
+        };
 
-        rec.log_static(
-            "live_camera",
-            &rerun::Image::from_elements(img.as_slice(), img.size().into(), rerun::ColorModel::RGB),
-        )?;
+        match transition {
+            Transition::None => {}
+            Transition::Status(status) => log_status(rec, &status_path, status)?,
+            Transition::Lost(status) => {
+                log_status(rec, &status_path, status)?;
+                // Dropping the camera closes it
+                camera = None;
+            }
+        }
     }
 
-    // NOTE: this is important to close the webcam properly, otherwise the app will hang
-    webcam.close()?;
     Ok(())
 }
 
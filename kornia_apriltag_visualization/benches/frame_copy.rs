@@ -0,0 +1,88 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use kornia_image::{Image, ImageSize, allocator::CpuAllocator};
+use kornia_imgproc::color::gray_from_rgb_u8;
+
+const RESOLUTIONS: [(&str, ImageSize); 3] = [
+    (
+        "640x480",
+        ImageSize {
+            width: 640,
+            height: 480,
+        },
+    ),
+    (
+        "1920x1080",
+        ImageSize {
+            width: 1920,
+            height: 1080,
+        },
+    ),
+    (
+        "3840x2160",
+        ImageSize {
+            width: 3840,
+            height: 2160,
+        },
+    ),
+];
+
+/// Compares the ways a grabbed frame gets into the pipeline, the copy of every RGB frame the
+/// camera source used to make against handing the frame over directly or grabbing GRAY8. The
+/// live path copies the frame into a recycled buffer of the frame queue, which is `rgb8/queue`.
+fn bench_frame_copy(c: &mut Criterion) {
+    for (resolution, size) in RESOLUTIONS {
+        let mut group = c.benchmark_group(format!("frame_copy/{resolution}"));
+        group.throughput(Throughput::Elements(1));
+
+        let data = (0..size.width * size.height * 3)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let rgb = Image::<u8, 3, _>::new(size, data, CpuAllocator).unwrap();
+        let mut gray = Image::from_size_val(size, 0u8, CpuAllocator).unwrap();
+        gray_from_rgb_u8(&rgb, &mut gray).unwrap();
+
+        // Copy into a newly allocated image, then convert to grayscale
+        group.bench_function(BenchmarkId::new("rgb8", "copy"), |b| {
+            let mut dst = Image::from_size_val(size, 0u8, CpuAllocator).unwrap();
+
+            b.iter(|| {
+                let copy =
+                    Image::from_size_slice(rgb.size(), rgb.as_slice(), CpuAllocator).unwrap();
+                gray_from_rgb_u8(&copy, &mut dst).unwrap();
+            });
+        });
+
+        // Copy into a recycled buffer, as the frame queue does, then convert to grayscale
+        group.bench_function(BenchmarkId::new("rgb8", "queue"), |b| {
+            let mut buffer = Image::from_size_val(size, 0u8, CpuAllocator).unwrap();
+            let mut dst = Image::from_size_val(size, 0u8, CpuAllocator).unwrap();
+
+            b.iter(|| {
+                buffer
+                    .as_slice_mut()
+                    .copy_from_slice(std::hint::black_box(rgb.as_slice()));
+                gray_from_rgb_u8(&buffer, &mut dst).unwrap();
+            });
+        });
+
+        // Convert the borrowed frame to grayscale
+        group.bench_function(BenchmarkId::new("rgb8", "zero_copy"), |b| {
+            let mut dst = Image::from_size_val(size, 0u8, CpuAllocator).unwrap();
+            b.iter(|| gray_from_rgb_u8(std::hint::black_box(&rgb), &mut dst).unwrap());
+        });
+
+        // Copy the GRAY8 frame into the pipeline buffer
+        group.bench_function(BenchmarkId::new("gray8", "copy"), |b| {
+            let mut dst = Image::from_size_val(size, 0u8, CpuAllocator).unwrap();
+            b.iter(|| {
+                dst.as_slice_mut()
+                    .copy_from_slice(std::hint::black_box(gray.as_slice()))
+            });
+        });
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_frame_copy);
+criterion_main!(benches);
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use kornia_apriltag::{
+    DecodeTagsConfig,
+    decoder::{GrayModelPair, decode_tags},
+    family::TagFamilyKind,
+    quad::fit_quads,
+    segmentation::{GradientInfo, find_connected_components, find_gradient_clusters},
+    threshold::{TileMinMax, adaptive_threshold},
+    union_find::UnionFind,
+    utils::Pixel,
+};
+use kornia_apriltag_visualization::synthetic::{Distortion, load_tag_images, render_scene};
+use kornia_image::{Image, ImageSize, allocator::CpuAllocator};
+use kornia_imgproc::color::gray_from_rgb_u8;
+use rand::{SeedableRng, rngs::StdRng, seq::IndexedRandom};
+
+const TAGS_DIR: &str = "./apriltag_imgs";
+
+const SIZE: ImageSize = ImageSize {
+    width: 640,
+    height: 480,
+};
+
+/// Tile sizes of `TileMinMax` used by the adaptive threshold.
+const TILE_SIZES: [usize; 4] = [2, 4, 8, 16];
+const TILE_SIZE: usize = 4;
+const MIN_WHITE_BLACK_DIFF: u8 = 20;
+
+type Clusters = HashMap<(usize, usize), Vec<GradientInfo>>;
+
+/// Fixed input of every stage, a synthetic scene with 4 tags.
+fn fixed_input() -> Image<u8, 1, CpuAllocator> {
+    let tag_images = load_tag_images(TAGS_DIR, TagFamilyKind::Tag36H11, 100).unwrap();
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let tags = tag_images.choose_multiple(&mut rng, 4).collect::<Vec<_>>();
+    let distortion = Distortion {
+        tag_size: 150.0,
+        rotation: 0.3,
+        perspective: 0.05,
+        blur_sigma: 0.5,
+        noise_sigma: 2.0,
+        lighting: 0.2,
+    };
+
+    render_scene(SIZE, &tags, &distortion, &mut rng)
+        .unwrap()
+        .image
+}
+
+fn binary_image(gray: &Image<u8, 1, CpuAllocator>) -> Image<Pixel, 1, CpuAllocator> {
+    let mut binary = Image::from_size_val(SIZE, Pixel::Skip, CpuAllocator).unwrap();
+    let mut tile_min_max = TileMinMax::new(SIZE, TILE_SIZE);
+    adaptive_threshold(gray, &mut binary, &mut tile_min_max, MIN_WHITE_BLACK_DIFF).unwrap();
+
+    binary
+}
+
+fn connected_components(binary: &Image<Pixel, 1, CpuAllocator>) -> UnionFind {
+    let mut uf = UnionFind::new(SIZE.width * SIZE.height);
+    find_connected_components(binary, &mut uf).unwrap();
+
+    uf
+}
+
+fn gradient_clusters(binary: &Image<Pixel, 1, CpuAllocator>) -> Clusters {
+    let mut uf = connected_components(binary);
+    let mut clusters = HashMap::new();
+    find_gradient_clusters(binary, &mut uf, &mut clusters);
+
+    clusters
+}
+
+fn bench_stages(c: &mut Criterion) {
+    let gray = fixed_input();
+    let rgb_data = gray
+        .as_slice()
+        .iter()
+        .flat_map(|&px| [px, px, px])
+        .collect();
+    let rgb = Image::<u8, 3, _>::new(SIZE, rgb_data, CpuAllocator).unwrap();
+    let binary = binary_image(&gray);
+
+    let mut group = c.benchmark_group("pipeline_stages");
+
+    group.bench_function("gray_from_rgb_u8", |b| {
+        let mut dst = Image::from_size_val(SIZE, 0u8, CpuAllocator).unwrap();
+        b.iter(|| gray_from_rgb_u8(&rgb, &mut dst).unwrap());
+    });
+
+    for tile_size in TILE_SIZES {
+        group.bench_with_input(
+            BenchmarkId::new("adaptive_threshold", tile_size),
+            &tile_size,
+            |b, &tile_size| {
+                let mut dst = Image::from_size_val(SIZE, Pixel::Skip, CpuAllocator).unwrap();
+                let mut tile_min_max = TileMinMax::new(SIZE, tile_size);
+
+                b.iter(|| {
+                    adaptive_threshold(&gray, &mut dst, &mut tile_min_max, MIN_WHITE_BLACK_DIFF)
+                        .unwrap()
+                });
+            },
+        );
+    }
+
+    group.bench_function("find_connected_components", |b| {
+        let mut uf = UnionFind::new(SIZE.width * SIZE.height);
+
+        b.iter(|| {
+            find_connected_components(&binary, &mut uf).unwrap();
+            uf.reset();
+        });
+    });
+
+    group.bench_function("find_gradient_clusters", |b| {
+        b.iter_batched(
+            || (connected_components(&binary), HashMap::new()),
+            |(mut uf, mut clusters)| {
+                find_gradient_clusters(&binary, &mut uf, &mut clusters);
+                clusters
+            },
+            BatchSize::LargeInput,
+        );
+    });
+
+    let config = DecodeTagsConfig::new(vec![TagFamilyKind::Tag36H11]);
+
+    group.bench_function("fit_quads", |b| {
+        b.iter_batched(
+            || gradient_clusters(&binary),
+            |mut clusters| fit_quads(&binary, &mut clusters, &config),
+            BatchSize::LargeInput,
+        );
+    });
+
+    group.bench_function("decode_tags", |b| {
+        let mut decode_config = DecodeTagsConfig::new(vec![TagFamilyKind::Tag36H11]);
+        let mut gray_model_pair = GrayModelPair::new();
+
+        b.iter_batched(
+            || fit_quads(&binary, &mut gradient_clusters(&binary), &config),
+            |mut quads| {
+                let detections =
+                    decode_tags(&gray, &mut quads, &mut decode_config, &mut gray_model_pair);
+                gray_model_pair.reset();
+                detections
+            },
+            BatchSize::LargeInput,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_stages);
+criterion_main!(benches);
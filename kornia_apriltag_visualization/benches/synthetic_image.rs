@@ -1,47 +1,158 @@
+use std::sync::Mutex;
+
 use apriltag::DetectorBuilder;
-use criterion::{Criterion, criterion_group, criterion_main};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
 use kornia_apriltag::{AprilTagDecoder, DecodeTagsConfig, family::TagFamilyKind};
-use kornia_image::{Image, allocator::CpuAllocator};
-use kornia_imgproc::color::gray_from_rgb_u8;
-use kornia_io::jpeg::read_image_jpeg_rgb8;
+use kornia_apriltag_visualization::synthetic::{
+    Distortion, Scene, TagImage, load_tag_images, render_scene,
+};
+use kornia_image::ImageSize;
+use rand::{SeedableRng, rngs::StdRng, seq::IndexedRandom};
+use rayon::prelude::*;
+
+const TAGS_DIR: &str = "./apriltag_imgs";
+
+const RESOLUTIONS: [(&str, ImageSize); 4] = [
+    (
+        "640x480",
+        ImageSize {
+            width: 640,
+            height: 480,
+        },
+    ),
+    (
+        "1280x720",
+        ImageSize {
+            width: 1280,
+            height: 720,
+        },
+    ),
+    (
+        "1920x1080",
+        ImageSize {
+            width: 1920,
+            height: 1080,
+        },
+    ),
+    (
+        "3840x2160",
+        ImageSize {
+            width: 3840,
+            height: 2160,
+        },
+    ),
+];
+
+/// Number of tags placed in a scene.
+const TAG_COUNTS: [usize; 3] = [1, 4, 16];
+
+/// Size of the tags relative to the grid cell they are placed in.
+const TAG_SCALES: [f32; 2] = [0.3, 0.6];
+
+/// Number of frames decoded per iteration by the multi-threaded variant.
+const BATCH_SIZE: usize = 16;
+
+fn generate_scenes(
+    tag_images: &[TagImage],
+    size: ImageSize,
+    tag_count: usize,
+    tag_scale: f32,
+    count: usize,
+) -> Vec<Scene> {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let columns = (tag_count as f32).sqrt().ceil() as usize;
+    let rows = tag_count.div_ceil(columns);
+    let cell = (size.width / columns).min(size.height / rows) as f32;
+
+    let distortion = Distortion {
+        tag_size: cell * tag_scale,
+        ..Default::default()
+    };
+
+    (0..count)
+        .map(|_| {
+            let tags = tag_images
+                .choose_multiple(&mut rng, tag_count)
+                .collect::<Vec<_>>();
+            render_scene(size, &tags, &distortion, &mut rng).unwrap()
+        })
+        .collect()
+}
 
 fn bench_decoding(c: &mut Criterion) {
-    let img = read_image_jpeg_rgb8("../../tests/data/apriltags_tag36h11.jpg").unwrap();
-    let mut gray_img = Image::from_size_val(img.size(), 0, CpuAllocator).unwrap();
-    gray_from_rgb_u8(&img, &mut gray_img).unwrap();
-
-    let mut apriltag_c_img =
-        apriltag::Image::zeros_with_stride(gray_img.width(), gray_img.height(), gray_img.width())
-            .unwrap();
-    gray_img
-        .as_slice()
-        .iter()
-        .zip(apriltag_c_img.as_slice_mut())
-        .for_each(|(src, dst)| {
-            *dst = *src;
-        });
-
-    let mut apriltag_c_detector = DetectorBuilder::new()
-        .add_family_bits(apriltag::Family::tag_36h11(), 2)
-        .build()
-        .unwrap();
-
-    apriltag_c_detector.set_decimation(1.0);
-
-    let kornia_detector_config = DecodeTagsConfig::new(vec![TagFamilyKind::Tag36H11]);
-    let mut kornia_detector =
-        AprilTagDecoder::new(kornia_detector_config, gray_img.size()).unwrap();
-
-    c.bench_function("kornia-apriltag", |b| {
-        b.iter(|| {
-            std::hint::black_box(kornia_detector.decode(&gray_img).unwrap());
-            kornia_detector.clear();
-        });
-    });
-
-    c.bench_function("apriltag-c", |b| {
-        b.iter(|| std::hint::black_box(apriltag_c_detector.detect(&apriltag_c_img)));
-    });
+    let tag_images = load_tag_images(TAGS_DIR, TagFamilyKind::Tag36H11, 100).unwrap();
+
+    for (resolution, size) in RESOLUTIONS {
+        let mut group = c.benchmark_group(format!("synthetic_image/{resolution}"));
+
+        if size.width > 1280 {
+            group.sample_size(10);
+        }
+
+        for tag_count in TAG_COUNTS {
+            for tag_scale in TAG_SCALES {
+                let parameter = format!("{tag_count}_tags/{tag_scale}_scale");
+                let scenes = generate_scenes(&tag_images, size, tag_count, tag_scale, BATCH_SIZE);
+                let gray_img = &scenes[0].image;
+
+                let mut apriltag_c_img =
+                    apriltag::Image::zeros_with_stride(size.width, size.height, size.width)
+                        .unwrap();
+                apriltag_c_img
+                    .as_slice_mut()
+                    .copy_from_slice(gray_img.as_slice());
+
+                let mut apriltag_c_detector = DetectorBuilder::new()
+                    .add_family_bits(apriltag::Family::tag_36h11(), 2)
+                    .build()
+                    .unwrap();
+
+                apriltag_c_detector.set_decimation(1.0);
+
+                let kornia_detector_config = DecodeTagsConfig::new(vec![TagFamilyKind::Tag36H11]);
+                let mut kornia_detector =
+                    AprilTagDecoder::new(kornia_detector_config, size).unwrap();
+
+                group.throughput(Throughput::Elements(1));
+
+                group.bench_function(BenchmarkId::new("kornia-apriltag", &parameter), |b| {
+                    b.iter(|| {
+                        std::hint::black_box(kornia_detector.decode(gray_img).unwrap());
+                        kornia_detector.clear();
+                    });
+                });
+
+                group.bench_function(BenchmarkId::new("apriltag-c", &parameter), |b| {
+                    b.iter(|| std::hint::black_box(apriltag_c_detector.detect(&apriltag_c_img)));
+                });
+
+                // One decoder per rayon thread, so the allocations aren't part of the measurement
+                let decoders = (0..rayon::current_num_threads())
+                    .map(|_| {
+                        let config = DecodeTagsConfig::new(vec![TagFamilyKind::Tag36H11]);
+                        Mutex::new(AprilTagDecoder::new(config, size).unwrap())
+                    })
+                    .collect::<Vec<_>>();
+
+                group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+
+                group.bench_function(BenchmarkId::new("kornia-apriltag-rayon", &parameter), |b| {
+                    b.iter(|| {
+                        scenes.par_iter().for_each(|scene| {
+                            let thread = rayon::current_thread_index().unwrap_or_default();
+                            let mut decoder = decoders[thread].lock().unwrap();
+
+                            std::hint::black_box(decoder.decode(&scene.image).unwrap());
+                            decoder.clear();
+                        });
+                    });
+                });
+            }
+        }
+
+        group.finish();
+    }
 }
 
 criterion_group!(benches, bench_decoding);
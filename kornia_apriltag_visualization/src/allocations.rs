@@ -0,0 +1,32 @@
+/// Heap allocations done while running a closure.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllocationStats {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// Measures the heap allocations done by `f`, returns `None` unless the `dhat-heap` feature is
+/// enabled.
+///
+/// NOTE: With the `dhat-heap` feature, the heap profiler must be running. The allocations of all
+/// threads are counted, including the ones done by the rerun sink in the background.
+pub fn measure_allocations<T>(f: impl FnOnce() -> T) -> (T, Option<AllocationStats>) {
+    #[cfg(feature = "dhat-heap")]
+    {
+        let before = dhat::HeapStats::get();
+        let result = f();
+        let after = dhat::HeapStats::get();
+
+        let stats = AllocationStats {
+            blocks: after.total_blocks - before.total_blocks,
+            bytes: after.total_bytes - before.total_bytes,
+        };
+
+        (result, Some(stats))
+    }
+
+    #[cfg(not(feature = "dhat-heap"))]
+    {
+        (f(), None)
+    }
+}
@@ -0,0 +1,284 @@
+use std::path::Path;
+
+use kornia_apriltag::decoder::Detection;
+use kornia_image::{
+    Image,
+    allocator::{CpuAllocator, ImageAllocator},
+};
+use kornia_imgproc::draw::draw_line;
+use kornia_io::{jpeg::write_image_jpeg_rgb8, png::write_image_png_rgb8};
+
+use crate::{
+    calibration::CameraCalibration, detection::tag_color, family::family_name, pose::TagPose,
+};
+
+/// 3x5 bitmaps of the digits, one row per byte with the leftmost pixel in the third bit.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Quality of the written JPEG images.
+const JPEG_QUALITY: u8 = 95;
+
+/// Colors of the x, y and z axis of the tag frame.
+const AXIS_COLORS: [[u8; 3]; 3] = [[255, 0, 0], [0, 255, 0], [0, 0, 255]];
+
+/// What the [`Renderer`] draws for every tag.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions {
+    pub line_thickness: usize,
+    /// Size of a pixel of the font of the ids in image pixels, the corner indices are half as big
+    pub font_scale: usize,
+    pub ids: bool,
+    pub corner_indices: bool,
+    pub centers: bool,
+    /// Only drawn with a calibration, see [`Renderer::with_calibration`]
+    pub pose_axes: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            line_thickness: 2,
+            font_scale: 3,
+            ids: true,
+            corner_indices: true,
+            centers: true,
+            pose_axes: true,
+        }
+    }
+}
+
+/// Draws the detected tags directly onto the image, so it can be saved or shared without a
+/// rerun viewer.
+#[derive(Debug, Clone, Default)]
+pub struct Renderer {
+    pub options: RenderOptions,
+    calibration: Option<CameraCalibration>,
+    /// Whether the pose axes are projected with the lens distortion, i.e. if the image is
+    /// distorted but the poses were estimated from undistorted corners
+    distort: bool,
+}
+
+impl Renderer {
+    pub fn new(options: RenderOptions) -> Self {
+        Self {
+            options,
+            calibration: None,
+            distort: false,
+        }
+    }
+
+    /// Draws the axes of the tag poses, projected with the calibration.
+    pub fn with_calibration(mut self, calibration: CameraCalibration, distort: bool) -> Self {
+        self.calibration = Some(calibration);
+        self.distort = distort;
+        self
+    }
+
+    /// Draws every tag in its own color. `poses` is either empty or has the pose of every
+    /// detection.
+    pub fn render(
+        &self,
+        img: &mut Image<u8, 3, CpuAllocator>,
+        detections: &[Detection],
+        poses: &[Option<TagPose>],
+    ) {
+        let options = &self.options;
+
+        for (i, tag) in detections.iter().enumerate() {
+            let color = tag_color(family_name(&tag.tag_family_kind), tag.id);
+            let corners = tag
+                .quad
+                .corners
+                .map(|corner| [corner.x.round() as i64, corner.y.round() as i64]);
+            let center = [tag.center.x.round() as i64, tag.center.y.round() as i64];
+
+            for j in 0..4 {
+                draw_line(
+                    img,
+                    (corners[j][0], corners[j][1]),
+                    (corners[(j + 1) % 4][0], corners[(j + 1) % 4][1]),
+                    color,
+                    options.line_thickness,
+                );
+            }
+
+            let pose = poses.get(i).copied().flatten();
+            if let (Some(calibration), Some(pose), true) =
+                (&self.calibration, pose, options.pose_axes)
+            {
+                self.draw_axes(img, calibration, &pose);
+            }
+
+            if options.centers {
+                let radius = options.line_thickness as i64 + 1;
+                fill_rect(
+                    img,
+                    [center[0] - radius, center[1] - radius],
+                    [center[0] + radius + 1, center[1] + radius + 1],
+                    color,
+                );
+            }
+
+            if options.corner_indices {
+                let scale = (options.font_scale / 2).max(1);
+
+                for (j, corner) in corners.iter().enumerate() {
+                    // Move the index outwards, so it doesn't cover the corner
+                    let offset = [corner[0] - center[0], corner[1] - center[1]];
+                    let length = ((offset[0].pow(2) + offset[1].pow(2)) as f64)
+                        .sqrt()
+                        .max(1.0);
+                    let shift = 6.0 * scale as f64 / length;
+                    let position = [
+                        corner[0] + (offset[0] as f64 * shift) as i64,
+                        corner[1] + (offset[1] as f64 * shift) as i64,
+                    ];
+
+                    draw_number(img, position, j as u32, color, scale);
+                }
+            }
+
+            if options.ids {
+                draw_number(img, center, tag.id as u32, color, options.font_scale);
+            }
+        }
+    }
+
+    fn draw_axes(
+        &self,
+        img: &mut Image<u8, 3, CpuAllocator>,
+        calibration: &CameraCalibration,
+        pose: &TagPose,
+    ) {
+        let half = pose.tag_size / 2.0;
+        let Some(origin) = pose.project(calibration, [0.0; 3], self.distort) else {
+            return;
+        };
+
+        for (axis, color) in AXIS_COLORS.iter().enumerate() {
+            let mut point = [0.0; 3];
+            point[axis] = half;
+
+            let Some(end) = pose.project(calibration, point, self.distort) else {
+                continue;
+            };
+
+            draw_line(
+                img,
+                (origin[0].round() as i64, origin[1].round() as i64),
+                (end[0].round() as i64, end[1].round() as i64),
+                *color,
+                self.options.line_thickness,
+            );
+        }
+    }
+}
+
+/// Copies `src` into `dst`, grayscale images are expanded to RGB.
+pub fn copy_to_rgb<A: ImageAllocator, const C: usize>(
+    src: &Image<u8, C, A>,
+    dst: &mut Image<u8, 3, CpuAllocator>,
+) {
+    if C == 3 {
+        dst.as_slice_mut().copy_from_slice(src.as_slice());
+    } else {
+        for (dst_px, src_px) in dst
+            .as_slice_mut()
+            .chunks_exact_mut(3)
+            .zip(src.as_slice().chunks_exact(C))
+        {
+            dst_px.fill(src_px[0]);
+        }
+    }
+}
+
+/// Draws the number centered at `center` on a black background, so it's readable on the tag.
+fn draw_number(
+    img: &mut Image<u8, 3, CpuAllocator>,
+    center: [i64; 2],
+    number: u32,
+    color: [u8; 3],
+    scale: usize,
+) {
+    let scale = scale as i64;
+    let digits = number.to_string();
+    let advance = 4 * scale;
+    let width = digits.len() as i64 * advance - scale;
+    let height = 5 * scale;
+    let origin = [center[0] - width / 2, center[1] - height / 2];
+
+    fill_rect(
+        img,
+        [origin[0] - scale, origin[1] - scale],
+        [origin[0] + width + scale, origin[1] + height + scale],
+        [0, 0, 0],
+    );
+
+    for (i, digit) in digits.bytes().enumerate() {
+        let bitmap = DIGITS[(digit - b'0') as usize];
+        let x0 = origin[0] + i as i64 * advance;
+
+        for (row, bits) in bitmap.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+
+                let x = x0 + col * scale;
+                let y = origin[1] + row as i64 * scale;
+                fill_rect(img, [x, y], [x + scale, y + scale], color);
+            }
+        }
+    }
+}
+
+/// Fills the pixels from `min` inclusive to `max` exclusive, clipped to the image.
+fn fill_rect(img: &mut Image<u8, 3, CpuAllocator>, min: [i64; 2], max: [i64; 2], color: [u8; 3]) {
+    let (width, height) = (img.width() as i64, img.height() as i64);
+    let (x0, x1) = (min[0].clamp(0, width), max[0].clamp(0, width));
+    let (y0, y1) = (min[1].clamp(0, height), max[1].clamp(0, height));
+
+    let pixels = img.as_slice_mut();
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let idx = (y * width + x) as usize * 3;
+            pixels[idx..idx + 3].copy_from_slice(&color);
+        }
+    }
+}
+
+/// Writes the image as PNG or JPEG, chosen by the extension of the path.
+pub fn save_image(
+    path: &Path,
+    img: &Image<u8, 3, CpuAllocator>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+
+    match ext.as_deref() {
+        Some("png") => write_image_png_rgb8(path, img)?,
+        Some("jpg" | "jpeg") => write_image_jpeg_rgb8(path, img, JPEG_QUALITY)?,
+        _ => {
+            return Err(format!(
+                "Unknown image format of {}, expected a .png, .jpg or .jpeg file",
+                path.display()
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
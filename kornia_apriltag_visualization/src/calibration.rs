@@ -0,0 +1,245 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Iterations used to invert the distortion models.
+const UNDISTORT_ITERATIONS: usize = 20;
+
+/// Lens distortion model of the camera.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DistortionModel {
+    /// Brown-Conrady model with the coefficients `[k1, k2, p1, p2, k3]`
+    #[default]
+    RadialTangential,
+    /// Kannala-Brandt model with the coefficients `[k1, k2, k3, k4]`
+    Fisheye,
+}
+
+/// Pinhole intrinsics and lens distortion of a camera.
+///
+/// Can be loaded from a JSON or YAML file with the following fields:
+///
+/// ```yaml
+/// width: 640
+/// height: 480
+/// fx: 600.0
+/// fy: 600.0
+/// cx: 320.0
+/// cy: 240.0
+/// distortion_model: radial_tangential # or fisheye
+/// distortion: [0.0, 0.0, 0.0, 0.0, 0.0]
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CameraCalibration {
+    pub width: u32,
+    pub height: u32,
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    #[serde(default)]
+    pub distortion_model: DistortionModel,
+    /// Distortion coefficients, missing coefficients are treated as `0`
+    #[serde(default)]
+    pub distortion: Vec<f64>,
+}
+
+impl CameraCalibration {
+    /// Loads the calibration, the format is picked from the file extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        let calibration = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&contents)?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents)?,
+            _ => return Err("Calibration file must be a .json, .yaml or .yml file".into()),
+        };
+
+        Ok(calibration)
+    }
+
+    /// Distortion coefficient at `index`, `0` if it's missing.
+    pub fn coefficient(&self, index: usize) -> f64 {
+        self.distortion.get(index).copied().unwrap_or_default()
+    }
+
+    /// Projects a point in the camera frame onto the image, without lens distortion.
+    pub fn project(&self, point: [f64; 3]) -> [f64; 2] {
+        [
+            self.fx * point[0] / point[2] + self.cx,
+            self.fy * point[1] / point[2] + self.cy,
+        ]
+    }
+
+    /// Applies the lens distortion to a point on the normalized image plane.
+    pub fn distort(&self, [x, y]: [f64; 2]) -> [f64; 2] {
+        let r2 = x * x + y * y;
+
+        match self.distortion_model {
+            DistortionModel::RadialTangential => {
+                let [k1, k2, p1, p2, k3] = [0, 1, 2, 3, 4].map(|i| self.coefficient(i));
+                let radial = 1.0 + r2 * (k1 + r2 * (k2 + r2 * k3));
+
+                [
+                    x * radial + 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x),
+                    y * radial + p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y,
+                ]
+            }
+            DistortionModel::Fisheye => {
+                let r = r2.sqrt();
+                if r < 1e-12 {
+                    return [x, y];
+                }
+
+                let theta = r.atan();
+                let scale = self.fisheye_theta_d(theta) / r;
+
+                [x * scale, y * scale]
+            }
+        }
+    }
+
+    /// Inverse of [`CameraCalibration::distort`], solved iteratively since the models have no
+    /// closed form inverse.
+    pub fn undistort(&self, [xd, yd]: [f64; 2]) -> [f64; 2] {
+        match self.distortion_model {
+            DistortionModel::RadialTangential => {
+                let [k1, k2, p1, p2, k3] = [0, 1, 2, 3, 4].map(|i| self.coefficient(i));
+                let [mut x, mut y] = [xd, yd];
+
+                for _ in 0..UNDISTORT_ITERATIONS {
+                    let r2 = x * x + y * y;
+                    let radial = 1.0 + r2 * (k1 + r2 * (k2 + r2 * k3));
+                    let dx = 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x);
+                    let dy = p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y;
+
+                    x = (xd - dx) / radial;
+                    y = (yd - dy) / radial;
+                }
+
+                [x, y]
+            }
+            DistortionModel::Fisheye => {
+                let theta_d = (xd * xd + yd * yd).sqrt();
+                if theta_d < 1e-12 {
+                    return [xd, yd];
+                }
+
+                // Newton's method on theta_d = theta * (1 + k1 theta^2 + ... + k4 theta^8)
+                let [k1, k2, k3, k4] = [0, 1, 2, 3].map(|i| self.coefficient(i));
+                let mut theta = theta_d;
+
+                for _ in 0..UNDISTORT_ITERATIONS {
+                    let t2 = theta * theta;
+                    let derivative =
+                        1.0 + t2 * (3.0 * k1 + t2 * (5.0 * k2 + t2 * (7.0 * k3 + t2 * 9.0 * k4)));
+                    let step = (self.fisheye_theta_d(theta) - theta_d) / derivative;
+                    theta -= step;
+
+                    if step.abs() < 1e-12 {
+                        break;
+                    }
+                }
+
+                let scale = theta.tan() / theta_d;
+                [xd * scale, yd * scale]
+            }
+        }
+    }
+
+    /// Removes the lens distortion from a pixel of the raw image.
+    pub fn undistort_pixel(&self, [u, v]: [f64; 2]) -> [f64; 2] {
+        let [x, y] = self.undistort([(u - self.cx) / self.fx, (v - self.cy) / self.fy]);
+        [self.fx * x + self.cx, self.fy * y + self.cy]
+    }
+
+    fn fisheye_theta_d(&self, theta: f64) -> f64 {
+        let [k1, k2, k3, k4] = [0, 1, 2, 3].map(|i| self.coefficient(i));
+        let t2 = theta * theta;
+
+        theta * (1.0 + t2 * (k1 + t2 * (k2 + t2 * (k3 + t2 * k4))))
+    }
+
+    /// Logs the pinhole model of the camera, so the 3D view shows its frustum.
+    pub fn log(
+        &self,
+        rec: &rerun::RecordingStream,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        rec.log_static(
+            path,
+            &rerun::Pinhole::from_focal_length_and_resolution(
+                [self.fx as f32, self.fy as f32],
+                [self.width as f32, self.height as f32],
+            )
+            .with_principal_point([self.cx as f32, self.cy as f32]),
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration(distortion_model: DistortionModel, distortion: Vec<f64>) -> CameraCalibration {
+        CameraCalibration {
+            width: 640,
+            height: 480,
+            fx: 600.0,
+            fy: 600.0,
+            cx: 320.0,
+            cy: 240.0,
+            distortion_model,
+            distortion,
+        }
+    }
+
+    fn assert_round_trip(calibration: &CameraCalibration) {
+        for x in [-0.5, -0.2, 0.0, 0.3, 0.5] {
+            for y in [-0.4, 0.0, 0.1, 0.4] {
+                let [ux, uy] = calibration.undistort(calibration.distort([x, y]));
+
+                assert!(
+                    (ux - x).abs() < 1e-6 && (uy - y).abs() < 1e-6,
+                    "({x}, {y}) is undistorted to ({ux}, {uy})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn radial_tangential_round_trip() {
+        assert_round_trip(&calibration(
+            DistortionModel::RadialTangential,
+            vec![-0.1, 0.02, 1e-3, -5e-4, 0.0],
+        ));
+    }
+
+    #[test]
+    fn fisheye_round_trip() {
+        assert_round_trip(&calibration(
+            DistortionModel::Fisheye,
+            vec![-0.01, 0.02, -0.005, 0.001],
+        ));
+    }
+
+    #[test]
+    fn pixel_round_trip() {
+        let calibration = calibration(
+            DistortionModel::RadialTangential,
+            vec![-0.1, 0.02, 1e-3, -5e-4],
+        );
+        let [x, y] = calibration.distort([0.25, -0.15]);
+        let [u, v] = calibration.undistort_pixel([
+            calibration.fx * x + calibration.cx,
+            calibration.fy * y + calibration.cy,
+        ]);
+
+        assert!((u - (600.0 * 0.25 + 320.0)).abs() < 1e-4);
+        assert!((v - (600.0 * -0.15 + 240.0)).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::SystemTime,
+};
+
+use argh::FromArgs;
+use kornia_apriltag::{AprilTagDecoder, DecodeTagsConfig};
+use kornia_apriltag_visualization::{
+    corners::corner_rmse,
+    family::parse_family,
+    source::{FrameHandler, FrameRef, FrameSource, PixelFormat},
+    timeline::set_frame_time,
+};
+use kornia_image::{
+    Image, ImageSize,
+    allocator::{CpuAllocator, ImageAllocator},
+};
+use kornia_imgproc::color::gray_from_rgb_u8;
+use kornia_io::jpeg::read_image_jpeg_rgb8;
+
+#[derive(FromArgs)]
+/// Run kornia-apriltag, apriltag-c and aprilgrid on the same frames and log their disagreements
+/// to rerun.
+struct Args {
+    /// the camera id to use, ignored if images are passed
+    #[argh(option, short = 'c', default = "0")]
+    camera_id: u32,
+
+    /// the frames per second to record
+    #[argh(option, short = 'f', default = "30")]
+    fps: u32,
+
+    /// JPEG image to use instead of the camera, can be repeated
+    #[argh(option, short = 'i')]
+    image: Vec<PathBuf>,
+
+    /// tag family to detect
+    #[argh(option, default = "String::from(\"tag36h11\")")]
+    family: String,
+
+    /// corner delta in pixels above which a tag is reported as a disagreement
+    #[argh(option, default = "1.0")]
+    max_corner_delta: f32,
+}
+
+/// Detectors compared against kornia-apriltag.
+const OTHER_DETECTORS: [&str; 2] = ["apriltag_c", "aprilgrid"];
+/// Colors of the tags of the [`OTHER_DETECTORS`].
+const OTHER_DETECTOR_COLORS: [[u8; 3]; 2] = [[255, 0, 0], [0, 128, 255]];
+
+/// Corners of a tag detected by any of the detectors.
+#[derive(Debug, Clone, Copy)]
+struct TagCorners {
+    id: u32,
+    corners: [[f32; 2]; 4],
+}
+
+/// Detections of the [`OTHER_DETECTORS`], `None` if the detector doesn't support the family.
+type OtherDetections = [Option<Vec<TagCorners>>; 2];
+
+/// Detectors of a single frame size, along with the buffers the frames are converted into.
+struct Detectors {
+    size: ImageSize,
+    gray: Image<u8, 1, CpuAllocator>,
+    kornia: AprilTagDecoder,
+    /// `None` if apriltag-c doesn't support the family
+    apriltag_c: Option<apriltag::Detector>,
+    apriltag_c_img: apriltag::Image,
+    /// `None` if aprilgrid doesn't support the family
+    aprilgrid: Option<aprilgrid::detector::TagDetector>,
+    aprilgrid_img: image::DynamicImage,
+}
+
+impl Detectors {
+    fn new(family: &str, size: ImageSize) -> Result<Self, Box<dyn std::error::Error>> {
+        let kind = parse_family(family).ok_or("Unknown tag family")?;
+        let kornia = AprilTagDecoder::new(DecodeTagsConfig::new(vec![kind]), size)?;
+
+        let apriltag_c = apriltag_c_family(family)
+            .map(|family| -> Result<_, Box<dyn std::error::Error>> {
+                let mut detector = apriltag::DetectorBuilder::new()
+                    .add_family_bits(family, 2)
+                    .build()?;
+                detector.set_decimation(1.0);
+                Ok(detector)
+            })
+            .transpose()?;
+
+        let apriltag_c_img =
+            apriltag::Image::zeros_with_stride(size.width, size.height, size.width)?;
+
+        let aprilgrid = aprilgrid_family(family)
+            .map(|family| aprilgrid::detector::TagDetector::new(&family, None));
+        let aprilgrid_img = image::DynamicImage::new_luma8(size.width as u32, size.height as u32);
+
+        let supported = [apriltag_c.is_some(), aprilgrid.is_some()];
+        for (name, supported) in OTHER_DETECTORS.iter().zip(supported) {
+            if !supported {
+                println!("{name} doesn't support {family}, it's skipped");
+            }
+        }
+
+        Ok(Self {
+            size,
+            gray: Image::from_size_val(size, 0u8, CpuAllocator)?,
+            kornia,
+            apriltag_c,
+            apriltag_c_img,
+            aprilgrid,
+            aprilgrid_img,
+        })
+    }
+
+    /// Returns the detections of kornia-apriltag and of the [`OTHER_DETECTORS`].
+    fn detect<A: ImageAllocator>(
+        &mut self,
+        img: &Image<u8, 3, A>,
+    ) -> Result<(Vec<TagCorners>, OtherDetections), Box<dyn std::error::Error>> {
+        gray_from_rgb_u8(img, &mut self.gray)?;
+        let gray = &self.gray;
+
+        let kornia = self
+            .kornia
+            .decode(gray)?
+            .iter()
+            .map(|tag| TagCorners {
+                id: tag.id as u32,
+                corners: tag.quad.corners.map(|corner| [corner.x, corner.y]),
+            })
+            .collect();
+        self.kornia.clear();
+
+        let apriltag_c = match &mut self.apriltag_c {
+            Some(detector) => {
+                self.apriltag_c_img
+                    .as_slice_mut()
+                    .copy_from_slice(gray.as_slice());
+
+                let tags = detector
+                    .detect(&self.apriltag_c_img)
+                    .iter()
+                    .map(|tag| TagCorners {
+                        id: tag.id() as u32,
+                        corners: tag.corners().map(|[x, y]| [x as f32, y as f32]),
+                    })
+                    .collect();
+                Some(tags)
+            }
+            None => None,
+        };
+
+        let aprilgrid = match &self.aprilgrid {
+            Some(detector) => {
+                self.aprilgrid_img
+                    .as_mut_luma8()
+                    .ok_or("The aprilgrid image isn't grayscale")?
+                    .copy_from_slice(gray.as_slice());
+
+                let tags = detector
+                    .detect(&self.aprilgrid_img)
+                    .into_iter()
+                    .filter(|(_, corners)| corners.len() == 4)
+                    .map(|(id, corners)| TagCorners {
+                        id,
+                        corners: [0, 1, 2, 3].map(|i| [corners[i].0, corners[i].1]),
+                    })
+                    .collect();
+                Some(tags)
+            }
+            None => None,
+        };
+
+        Ok((kornia, [apriltag_c, aprilgrid]))
+    }
+}
+
+/// Disagreement of a detector with kornia-apriltag in a single frame.
+#[derive(Debug, Default)]
+struct Disagreement {
+    /// Ids detected by the other detector but not by kornia-apriltag
+    missed_by_kornia: Vec<u32>,
+    /// Ids detected by kornia-apriltag but not by the other detector
+    missed_by_other: Vec<u32>,
+    /// RMSE between the corners of the tags detected by both
+    corner_deltas: Vec<(u32, f32)>,
+}
+
+impl Disagreement {
+    fn new(kornia: &[TagCorners], other: &[TagCorners]) -> Self {
+        let kornia = kornia
+            .iter()
+            .map(|tag| (tag.id, tag))
+            .collect::<BTreeMap<_, _>>();
+        let other = other
+            .iter()
+            .map(|tag| (tag.id, tag))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut disagreement = Self::default();
+
+        for (id, tag) in &kornia {
+            match other.get(id) {
+                Some(other_tag) => disagreement
+                    .corner_deltas
+                    .push((*id, corner_rmse(&tag.corners, &other_tag.corners))),
+                None => disagreement.missed_by_other.push(*id),
+            }
+        }
+
+        disagreement.missed_by_kornia = other
+            .keys()
+            .filter(|id| !kornia.contains_key(id))
+            .copied()
+            .collect();
+
+        disagreement
+    }
+
+    fn mean_corner_delta(&self) -> Option<f32> {
+        (!self.corner_deltas.is_empty()).then(|| {
+            self.corner_deltas
+                .iter()
+                .map(|(_, delta)| delta)
+                .sum::<f32>()
+                / self.corner_deltas.len() as f32
+        })
+    }
+}
+
+/// Runs the detectors on every frame and logs their disagreements.
+struct Comparison<'a> {
+    args: &'a Args,
+    rec: rerun::RecordingStream,
+    /// Created for the size of the first frame, and again whenever the size changes
+    detectors: Option<Detectors>,
+    frame_idx: u64,
+}
+
+impl Comparison<'_> {
+    fn process<A: ImageAllocator>(
+        &mut self,
+        img: &Image<u8, 3, A>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let rec = &self.rec;
+        set_frame_time(rec, self.frame_idx, SystemTime::now());
+
+        if self.detectors.as_ref().is_none_or(|d| d.size != img.size()) {
+            self.detectors = Some(Detectors::new(&self.args.family, img.size())?);
+        }
+
+        let (kornia, others) = self
+            .detectors
+            .as_mut()
+            .ok_or("Detectors aren't initialized")?
+            .detect(img)?;
+
+        rec.log(
+            "Comparison",
+            &rerun::Image::from_elements(img.as_slice(), img.size().into(), rerun::ColorModel::RGB),
+        )?;
+
+        log_corners(rec, "Comparison/kornia", &kornia, [0, 255, 0])?;
+
+        for ((name, color), other) in OTHER_DETECTORS
+            .iter()
+            .zip(OTHER_DETECTOR_COLORS)
+            .zip(&others)
+        {
+            // Without support for the family, every tag would be reported as missed
+            let Some(other) = other else {
+                continue;
+            };
+
+            log_corners(rec, &format!("Comparison/{name}"), other, color)?;
+
+            let disagreement = Disagreement::new(&kornia, other);
+            log_disagreement(
+                rec,
+                self.frame_idx,
+                name,
+                &disagreement,
+                self.args.max_corner_delta,
+            )?;
+        }
+
+        self.frame_idx += 1;
+        Ok(())
+    }
+}
+
+impl FrameHandler for Comparison<'_> {
+    fn handle<A: ImageAllocator>(
+        &mut self,
+        frame: FrameRef<'_, A>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let FrameRef::Rgb(img) = frame else {
+            return Err("Only RGB frames can be compared".into());
+        };
+
+        // The GStreamer buffer is only borrowed while it's converted, it isn't copied
+        self.process(img)
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = argh::from_env();
+    let rec = rerun::RecordingStreamBuilder::new("Kornia-apriltag comparison").spawn()?;
+
+    let mut comparison = Comparison {
+        args: &args,
+        rec,
+        detectors: None,
+        frame_idx: 0,
+    };
+
+    if !args.image.is_empty() {
+        for path in &args.image {
+            let img = read_image_jpeg_rgb8(path)?;
+            comparison.process(&img)?;
+        }
+
+        return Ok(());
+    }
+
+    // The camera is closed when the source is dropped, so a failed frame doesn't leave the
+    // GStreamer pipeline running
+    let mut webcam = FrameSource::camera(
+        args.camera_id,
+        args.fps,
+        ImageSize {
+            width: 640,
+            height: 480,
+        },
+        PixelFormat::Rgb8,
+    )?;
+
+    let cancel_token = Arc::new(AtomicBool::new(false));
+
+    ctrlc::set_handler({
+        let cancel_token = cancel_token.clone();
+        move || {
+            println!("Received Ctrl-C signal. Sending cancel signal!");
+            cancel_token.store(true, Ordering::SeqCst);
+        }
+    })?;
+
+    while !cancel_token.load(Ordering::SeqCst) {
+        webcam.grab_with(&mut comparison)?;
+    }
+
+    webcam.close()?;
+    println!("Finished recording. Closing app.");
+
+    Ok(())
+}
+
+fn log_corners(
+    rec: &rerun::RecordingStream,
+    path: &str,
+    tags: &[TagCorners],
+    [r, g, b]: [u8; 3],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let strips = tags.iter().map(|tag| {
+        let [c0, c1, c2, c3] = tag.corners;
+        [c0, c1, c2, c3, c0]
+    });
+
+    rec.log(
+        path,
+        &rerun::LineStrips2D::new(strips)
+            .with_colors([rerun::Color::from_rgb(r, g, b)])
+            .with_labels(tags.iter().map(|tag| tag.id.to_string())),
+    )?;
+
+    Ok(())
+}
+
+fn log_disagreement(
+    rec: &rerun::RecordingStream,
+    frame_idx: u64,
+    name: &str,
+    disagreement: &Disagreement,
+    max_corner_delta: f32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    rec.log(
+        format!("Metrics/{name}/missed_by_kornia"),
+        &rerun::Scalars::new([disagreement.missed_by_kornia.len() as f64]),
+    )?;
+    rec.log(
+        format!("Metrics/{name}/missed_by_{name}"),
+        &rerun::Scalars::new([disagreement.missed_by_other.len() as f64]),
+    )?;
+
+    if let Some(delta) = disagreement.mean_corner_delta() {
+        rec.log(
+            format!("Metrics/{name}/corner_delta"),
+            &rerun::Scalars::new([delta as f64]),
+        )?;
+    }
+
+    let large_deltas = disagreement
+        .corner_deltas
+        .iter()
+        .filter(|(_, delta)| *delta > max_corner_delta)
+        .map(|(id, delta)| format!("{id} ({delta:.2} px)"))
+        .collect::<Vec<_>>();
+
+    if disagreement.missed_by_kornia.is_empty()
+        && disagreement.missed_by_other.is_empty()
+        && large_deltas.is_empty()
+    {
+        return Ok(());
+    }
+
+    let text = format!(
+        "frame {frame_idx}, kornia vs {name}: missed by kornia {:?}, missed by {name} {:?}, corner deltas [{}]",
+        disagreement.missed_by_kornia,
+        disagreement.missed_by_other,
+        large_deltas.join(", ")
+    );
+    println!("{text}");
+
+    rec.log(
+        format!("Metrics/{name}/disagreements"),
+        &rerun::TextLog::new(text).with_level(rerun::TextLogLevel::WARN),
+    )?;
+
+    Ok(())
+}
+
+fn apriltag_c_family(name: &str) -> Option<apriltag::Family> {
+    let family = match name {
+        "tag16h5" => apriltag::Family::tag_16h5(),
+        "tag25h9" => apriltag::Family::tag_25h9(),
+        "tag36h10" => apriltag::Family::tag_36h10(),
+        "tag36h11" => apriltag::Family::tag_36h11(),
+        "tagCircle21h7" => apriltag::Family::tag_circle_21h7(),
+        "tagCircle49h12" => apriltag::Family::tag_circle_49h12(),
+        "tagCustom48h12" => apriltag::Family::tag_custom_48h12(),
+        "tagStandard41h12" => apriltag::Family::tag_standard_41h12(),
+        "tagStandard52h13" => apriltag::Family::tag_standard_52h13(),
+        _ => return None,
+    };
+
+    Some(family)
+}
+
+fn aprilgrid_family(name: &str) -> Option<aprilgrid::TagFamily> {
+    let family = match name {
+        "tag16h5" => aprilgrid::TagFamily::T16H5,
+        "tag25h9" => aprilgrid::TagFamily::T25H9,
+        "tag36h11" => aprilgrid::TagFamily::T36H11,
+        _ => return None,
+    };
+
+    Some(family)
+}
@@ -0,0 +1,27 @@
+/// Euclidean distance between two points.
+pub fn distance(a: [f32; 2], b: [f32; 2]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+}
+
+/// Sum of the squared distances between the corners of two quads, for the cyclic order and
+/// winding which fits best, as the corner order of different detectors doesn't have to agree.
+pub fn squared_corner_error(a: &[[f32; 2]; 4], b: &[[f32; 2]; 4]) -> f32 {
+    let mut reversed = *b;
+    reversed.reverse();
+
+    [b, &reversed]
+        .into_iter()
+        .flat_map(|corners| {
+            (0..4).map(move |shift| {
+                (0..4)
+                    .map(|i| distance(a[i], corners[(i + shift) % 4]).powi(2))
+                    .sum::<f32>()
+            })
+        })
+        .fold(f32::MAX, f32::min)
+}
+
+/// Root mean square distance between the corners of two quads, see [`squared_corner_error`].
+pub fn corner_rmse(a: &[[f32; 2]; 4], b: &[[f32; 2]; 4]) -> f32 {
+    (squared_corner_error(a, b) / 4.0).sqrt()
+}
@@ -0,0 +1,398 @@
+use kornia_apriltag::{family::TagFamily, quad::Quad};
+use kornia_image::{Image, allocator::ImageAllocator};
+
+use crate::homography::{homography_from_corners, project};
+
+/// Number of pixels used to render a single bit cell of the rectified tag.
+const PIXELS_PER_CELL: usize = 8;
+const BIT_RADIUS: f32 = 2.0;
+
+/// Points along the border of the tag used to fit the gray models, same as apriltag-c.
+/// Each entry is `(x0, y0, dx, dy, is_white)` in bit cell coordinates.
+fn border_patterns(width_at_border: f32) -> [(f32, f32, f32, f32, bool); 8] {
+    [
+        // left white column
+        (-0.5, 0.5, 0.0, 1.0, true),
+        // left black column
+        (0.5, 0.5, 0.0, 1.0, false),
+        // right white column
+        (width_at_border + 0.5, 0.5, 0.0, 1.0, true),
+        // right black column
+        (width_at_border - 0.5, 0.5, 0.0, 1.0, false),
+        // top white row
+        (0.5, -0.5, 1.0, 0.0, true),
+        // top black row
+        (0.5, 0.5, 1.0, 0.0, false),
+        // bottom white row
+        (0.5, width_at_border + 0.5, 1.0, 0.0, true),
+        // bottom black row
+        (0.5, width_at_border - 0.5, 1.0, 0.0, false),
+    ]
+}
+
+/// Linear model `v = a * x + b * y + c` of the gray values over the tag, fitted with least squares.
+#[derive(Debug, Default, Clone, Copy)]
+struct GrayModel {
+    a: [[f32; 3]; 3],
+    b: [f32; 3],
+    count: usize,
+    coefficients: [f32; 3],
+}
+
+impl GrayModel {
+    fn add(&mut self, x: f32, y: f32, value: f32) {
+        let row = [x, y, 1.0];
+
+        for i in 0..3 {
+            for j in 0..3 {
+                self.a[i][j] += row[i] * row[j];
+            }
+            self.b[i] += row[i] * value;
+        }
+
+        self.count += 1;
+    }
+
+    fn solve(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+
+        let a = self.a;
+        let det = a[0][0] * (a[1][1] * a[2][2] - a[1][2] * a[2][1])
+            - a[0][1] * (a[1][0] * a[2][2] - a[1][2] * a[2][0])
+            + a[0][2] * (a[1][0] * a[2][1] - a[1][1] * a[2][0]);
+
+        if self.count < 3 || det.abs() < 1e-6 {
+            // Not enough points to fit a plane, fallback to the mean
+            self.coefficients = [0.0, 0.0, self.b[2] / self.count as f32];
+            return;
+        }
+
+        // Cramer's rule
+        for (i, coefficient) in self.coefficients.iter_mut().enumerate() {
+            let mut m = a;
+            for (row, b) in m.iter_mut().zip(self.b) {
+                row[i] = b;
+            }
+
+            *coefficient = (m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+                - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+                + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]))
+                / det;
+        }
+    }
+
+    fn interpolate(&self, x: f32, y: f32) -> f32 {
+        let [a, b, c] = self.coefficients;
+        a * x + b * y + c
+    }
+}
+
+/// Sampled bit of a quad.
+#[derive(Debug, Clone, Copy)]
+pub struct SampledBit {
+    /// Position of the bit in the rectified patch
+    pub pos: [f32; 2],
+    /// Difference between the sampled gray value and the threshold, after sharpening
+    pub value: f32,
+}
+
+impl SampledBit {
+    pub fn is_white(&self) -> bool {
+        self.value > 0.0
+    }
+}
+
+/// Closest code of the family to the sampled bits.
+#[derive(Debug, Clone, Copy)]
+pub struct CodeMatch {
+    pub id: usize,
+    pub rotation: usize,
+    pub hamming: u32,
+}
+
+/// Everything computed while decoding a single quad against a family.
+pub struct QuadDecodeDebug {
+    /// Size of the rectified patch and threshold surface images in pixels
+    pub patch_size: usize,
+    /// Rectified tag, sampled from the grayscale image
+    pub patch: Vec<u8>,
+    /// Threshold between the fitted black and white models over the tag
+    pub threshold: Vec<u8>,
+    pub bits: Vec<SampledBit>,
+    pub code: u64,
+    pub best_match: Option<CodeMatch>,
+}
+
+/// Reproduces the sampling and classification of the bits done in `decode_tags`, keeping all the
+/// intermediate values so they can be visualized.
+pub fn debug_decode_quad<A: ImageAllocator>(
+    src: &Image<u8, 1, A>,
+    quad: &Quad,
+    family: &TagFamily,
+    decode_sharpening: f32,
+) -> Option<QuadDecodeDebug> {
+    let homography = homography_from_corners(&quad.corners)?;
+
+    let width_at_border = family.width_at_border as f32;
+    let total_width = family.total_width;
+    let min_coord = (family.width_at_border as i32 - total_width as i32) / 2;
+
+    let to_tag = |cell_x: f32, cell_y: f32| {
+        (
+            2.0 * (cell_x / width_at_border - 0.5),
+            2.0 * (cell_y / width_at_border - 0.5),
+        )
+    };
+
+    // Fit the gray models on the border of the tag
+    let mut white_model = GrayModel::default();
+    let mut black_model = GrayModel::default();
+
+    for (x0, y0, dx, dy, is_white) in border_patterns(width_at_border) {
+        for i in 0..family.width_at_border {
+            let (tag_x, tag_y) = to_tag(x0 + i as f32 * dx, y0 + i as f32 * dy);
+            let [px, py] = project(&homography, tag_x, tag_y);
+
+            let Some(value) = pixel_value(src, px as isize, py as isize) else {
+                continue;
+            };
+
+            if is_white ^ family.reversed_border {
+                white_model.add(tag_x, tag_y, value);
+            } else {
+                black_model.add(tag_x, tag_y, value);
+            }
+        }
+    }
+
+    white_model.solve();
+    black_model.solve();
+
+    // Sample the bits of the tag
+    let mut values = vec![0.0f32; total_width * total_width];
+
+    for (&bit_x, &bit_y) in family.bit_x.iter().zip(&family.bit_y) {
+        let (tag_x, tag_y) = to_tag(bit_x as f32 + 0.5, bit_y as f32 + 0.5);
+        let [px, py] = project(&homography, tag_x, tag_y);
+
+        let value = interpolate(src, px, py).unwrap_or_default();
+        let threshold =
+            (black_model.interpolate(tag_x, tag_y) + white_model.interpolate(tag_x, tag_y)) / 2.0;
+
+        let idx =
+            (bit_y as i32 - min_coord) as usize * total_width + (bit_x as i32 - min_coord) as usize;
+        values[idx] = value - threshold;
+    }
+
+    sharpen(&mut values, total_width, decode_sharpening);
+
+    let mut code = 0u64;
+    let mut bits = Vec::with_capacity(family.nbits);
+
+    for (&bit_x, &bit_y) in family.bit_x.iter().zip(&family.bit_y) {
+        let cell_x = (bit_x as i32 - min_coord) as usize;
+        let cell_y = (bit_y as i32 - min_coord) as usize;
+        let value = values[cell_y * total_width + cell_x];
+
+        code <<= 1;
+        if value > 0.0 {
+            code |= 1;
+        }
+
+        bits.push(SampledBit {
+            pos: [
+                (cell_x as f32 + 0.5) * PIXELS_PER_CELL as f32,
+                (cell_y as f32 + 0.5) * PIXELS_PER_CELL as f32,
+            ],
+            value,
+        });
+    }
+
+    // Render the rectified tag and threshold surface
+    let patch_size = total_width * PIXELS_PER_CELL;
+    let mut patch = vec![0u8; patch_size * patch_size];
+    let mut threshold = vec![0u8; patch_size * patch_size];
+
+    for y in 0..patch_size {
+        for x in 0..patch_size {
+            let cell_x = (x as f32 + 0.5) / PIXELS_PER_CELL as f32 + min_coord as f32;
+            let cell_y = (y as f32 + 0.5) / PIXELS_PER_CELL as f32 + min_coord as f32;
+            let (tag_x, tag_y) = to_tag(cell_x, cell_y);
+            let [px, py] = project(&homography, tag_x, tag_y);
+
+            let idx = y * patch_size + x;
+            patch[idx] = interpolate(src, px, py).unwrap_or_default() as u8;
+            threshold[idx] = ((black_model.interpolate(tag_x, tag_y)
+                + white_model.interpolate(tag_x, tag_y))
+                / 2.0)
+                .clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    Some(QuadDecodeDebug {
+        patch_size,
+        patch,
+        threshold,
+        bits,
+        code,
+        best_match: best_match(code, family),
+    })
+}
+
+/// Logs the decoding internals of a quad under `path`.
+pub fn log_quad_decode(
+    rec: &rerun::RecordingStream,
+    path: &str,
+    debug: &QuadDecodeDebug,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let resolution = [debug.patch_size as u32, debug.patch_size as u32];
+
+    rec.log(
+        format!("{path}/patch"),
+        &rerun::Image::from_elements(&debug.patch, resolution, rerun::ColorModel::L),
+    )?;
+
+    let (positions, colors): (Vec<_>, Vec<_>) = debug
+        .bits
+        .iter()
+        .map(|bit| {
+            let color = if bit.is_white() {
+                rerun::Color::from_rgb(255, 255, 255)
+            } else {
+                rerun::Color::from_rgb(0, 0, 0)
+            };
+            (bit.pos, color)
+        })
+        .unzip();
+
+    rec.log(
+        format!("{path}/patch/bits"),
+        &rerun::Points2D::new(positions)
+            .with_colors(colors)
+            .with_radii([BIT_RADIUS])
+            .with_labels(debug.bits.iter().map(|bit| format!("{:.0}", bit.value))),
+    )?;
+
+    rec.log(
+        format!("{path}/threshold"),
+        &rerun::Image::from_elements(&debug.threshold, resolution, rerun::ColorModel::L),
+    )?;
+
+    let text = match debug.best_match {
+        Some(best) => format!(
+            "code: {:#x}, best match: id {} (rotation: {}, hamming: {})",
+            debug.code, best.id, best.rotation, best.hamming
+        ),
+        None => format!("code: {:#x}, no match", debug.code),
+    };
+    rec.log(format!("{path}/code"), &rerun::TextLog::new(text))?;
+
+    if let Some(best) = debug.best_match {
+        rec.log(
+            format!("{path}/hamming"),
+            &rerun::Scalars::new([best.hamming as f64]),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn pixel_value<A: ImageAllocator>(src: &Image<u8, 1, A>, x: isize, y: isize) -> Option<f32> {
+    if x < 0 || y < 0 || x as usize >= src.width() || y as usize >= src.height() {
+        return None;
+    }
+
+    Some(src.as_slice()[y as usize * src.width() + x as usize] as f32)
+}
+
+/// Bilinear interpolation of the gray value at a sub-pixel position.
+fn interpolate<A: ImageAllocator>(src: &Image<u8, 1, A>, x: f32, y: f32) -> Option<f32> {
+    let x = x - 0.5;
+    let y = y - 0.5;
+    let x1 = x.floor() as isize;
+    let y1 = y.floor() as isize;
+    let dx = x - x1 as f32;
+    let dy = y - y1 as f32;
+
+    let v00 = pixel_value(src, x1, y1)?;
+    let v10 = pixel_value(src, x1 + 1, y1)?;
+    let v01 = pixel_value(src, x1, y1 + 1)?;
+    let v11 = pixel_value(src, x1 + 1, y1 + 1)?;
+
+    Some(
+        v00 * (1.0 - dx) * (1.0 - dy)
+            + v10 * dx * (1.0 - dy)
+            + v01 * (1.0 - dx) * dy
+            + v11 * dx * dy,
+    )
+}
+
+/// Sharpens the sampled values with a laplacian kernel, same as apriltag-c.
+fn sharpen(values: &mut [f32], width: usize, decode_sharpening: f32) {
+    let mut sharpened = vec![0.0f32; values.len()];
+
+    for y in 0..width {
+        for x in 0..width {
+            let mut sum = 4.0 * values[y * width + x];
+
+            if x > 0 {
+                sum -= values[y * width + x - 1];
+            }
+            if x + 1 < width {
+                sum -= values[y * width + x + 1];
+            }
+            if y > 0 {
+                sum -= values[(y - 1) * width + x];
+            }
+            if y + 1 < width {
+                sum -= values[(y + 1) * width + x];
+            }
+
+            sharpened[y * width + x] = sum;
+        }
+    }
+
+    for (value, sharpened) in values.iter_mut().zip(sharpened) {
+        *value += decode_sharpening * sharpened;
+    }
+}
+
+/// Finds the code of the family with the smallest hamming distance to `code`, in any rotation.
+fn best_match(code: u64, family: &TagFamily) -> Option<CodeMatch> {
+    let nbits = family.nbits as u32;
+    let mut best: Option<CodeMatch> = None;
+
+    for (id, &family_code) in family.code_data.iter().enumerate() {
+        let mut rotated = family_code as u64;
+
+        for rotation in 0..4 {
+            let hamming = (code ^ rotated).count_ones();
+
+            if best.is_none_or(|best| hamming < best.hamming) {
+                best = Some(CodeMatch {
+                    id,
+                    rotation,
+                    hamming,
+                });
+            }
+
+            rotated = rotate90(rotated, nbits);
+        }
+    }
+
+    best
+}
+
+/// Rotates the code by 90 degrees clockwise, port of `rotate90` from apriltag-c.
+fn rotate90(code: u64, nbits: u32) -> u64 {
+    let (p, l) = if nbits % 4 == 1 {
+        (nbits - 1, 1)
+    } else {
+        (nbits, 0)
+    };
+
+    let code = ((code >> l) << (p / 4 + l)) | ((code >> (3 * p / 4 + l)) << l) | (code & l as u64);
+    code & ((1u64 << nbits) - 1)
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use kornia_apriltag::{decoder::Detection, family::TagFamilyKind};
+use rand::{Rng, SeedableRng};
+
+use crate::{
+    family::{FAMILY_NAMES, family_name},
+    homography::project,
+    palette::hsv_to_rgb,
+    pose::{TagPose, log_tag_pose},
+};
+
+/// Keypoint id used for the tag center, the corners use the ids `0..4`.
+const CENTER_KEYPOINT_ID: u16 = 4;
+const CORNER_RADIUS: f32 = 4.0;
+const CENTER_RADIUS: f32 = 3.0;
+
+/// Logs every detected tag as a set of structured rerun entities under
+/// `<entity_path>/<family>/<id>`, so tags can be filtered and inspected individually.
+pub struct DetectionLogger {
+    entity_path: String,
+    families: Vec<TagFamilyKind>,
+    /// Tag ids per family that are part of the logged annotation contexts
+    known_ids: BTreeMap<&'static str, BTreeSet<u16>>,
+    /// Entity path of the 3D tag poses, if poses are logged
+    pose_path: Option<String>,
+    /// Entity path of the number of detected tags per family
+    counts_path: String,
+    /// Entity paths of the tags logged in the previous frame
+    previous_paths: BTreeSet<String>,
+}
+
+impl DetectionLogger {
+    pub fn new(entity_path: impl Into<String>, families: &[TagFamilyKind]) -> Self {
+        Self {
+            entity_path: entity_path.into(),
+            families: families.to_vec(),
+            known_ids: BTreeMap::new(),
+            pose_path: None,
+            counts_path: "Detection Counts".to_string(),
+            previous_paths: BTreeSet::new(),
+        }
+    }
+
+    /// Logs the number of detected tags per family under `<counts_path>/<family>`.
+    pub fn with_counts_path(mut self, counts_path: impl Into<String>) -> Self {
+        self.counts_path = counts_path.into();
+        self
+    }
+
+    /// Also logs the tag poses in 3D under `<pose_path>/<family>/<id>`.
+    pub fn with_pose_path(mut self, pose_path: impl Into<String>) -> Self {
+        self.pose_path = Some(pose_path.into());
+        self
+    }
+
+    /// Logs the detections of a frame. `poses` holds the pose of every detection at the same
+    /// index and can be empty when no calibration is available.
+    pub fn log(
+        &mut self,
+        rec: &rerun::RecordingStream,
+        detections: &[Detection],
+        poses: &[Option<TagPose>],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut current_paths = BTreeSet::new();
+        let mut new_ids = BTreeSet::new();
+
+        for (i, tag) in detections.iter().enumerate() {
+            let family = family_name(&tag.tag_family_kind);
+
+            if self.known_ids.entry(family).or_default().insert(tag.id) {
+                new_ids.insert(family);
+            }
+
+            let path = format!("{}/{family}/{}", self.entity_path, tag.id);
+            log_detection(rec, &path, tag)?;
+            current_paths.insert(path);
+
+            if let (Some(pose_path), Some(Some(pose))) = (&self.pose_path, poses.get(i)) {
+                let path = format!("{pose_path}/{family}/{}", tag.id);
+                let label = format!("{family}:{}", tag.id);
+                log_tag_pose(rec, &path, pose, &label, tag_color(family, tag.id))?;
+
+                rec.log(
+                    format!(
+                        "{}/{family}/{}/reprojection_error",
+                        self.entity_path, tag.id
+                    ),
+                    &rerun::Scalars::new([pose.reprojection_error]),
+                )?;
+                current_paths.insert(path);
+            }
+        }
+
+        for family in new_ids {
+            self.log_annotation_context(rec, family)?;
+        }
+
+        // Clear the tags which aren't visible anymore, otherwise they will stay in the viewer
+        for path in self.previous_paths.difference(&current_paths) {
+            rec.log(path.as_str(), &rerun::Clear::recursive())?;
+        }
+
+        self.previous_paths = current_paths;
+
+        // Summary of the detected tags per family
+        for kind in &self.families {
+            let family = family_name(kind);
+            let count = detections
+                .iter()
+                .filter(|tag| family_name(&tag.tag_family_kind) == family)
+                .count();
+
+            rec.log(
+                format!("{}/{family}", self.counts_path),
+                &rerun::Scalars::new([count as f64]),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Logs the annotation context of the family, mapping the tag ids to their colors.
+    fn log_annotation_context(
+        &self,
+        rec: &rerun::RecordingStream,
+        family: &'static str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(ids) = self.known_ids.get(family) else {
+            return Ok(());
+        };
+
+        let classes = ids.iter().map(|&id| {
+            let [r, g, b] = tag_color(family, id);
+
+            rerun::datatypes::ClassDescription {
+                info: rerun::datatypes::AnnotationInfo {
+                    id,
+                    label: Some(format!("{family}:{id}").into()),
+                    color: Some(rerun::Rgba32::from_rgb(r, g, b)),
+                },
+                keypoint_annotations: vec![
+                    (0, "corner 0").into(),
+                    (1, "corner 1").into(),
+                    (2, "corner 2").into(),
+                    (3, "corner 3").into(),
+                    (CENTER_KEYPOINT_ID, "center").into(),
+                ],
+                keypoint_connections: rerun::KeypointPair::vec_from([
+                    (0, 1),
+                    (1, 2),
+                    (2, 3),
+                    (3, 0),
+                ]),
+            }
+        });
+
+        // Every family has its own context, so the same id in different families doesn't collide
+        rec.log_static(
+            format!("{}/{family}", self.entity_path),
+            &rerun::AnnotationContext::new(classes),
+        )?;
+
+        Ok(())
+    }
+}
+
+fn log_detection(
+    rec: &rerun::RecordingStream,
+    path: &str,
+    tag: &Detection,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let family = family_name(&tag.tag_family_kind);
+    let corners = tag.quad.corners.map(|corner| [corner.x, corner.y]);
+    let center = [tag.center.x, tag.center.y];
+
+    rec.log(
+        format!("{path}/corners"),
+        &rerun::Points2D::new(corners)
+            .with_class_ids([tag.id; 4])
+            .with_keypoint_ids([0, 1, 2, 3])
+            .with_radii([CORNER_RADIUS]),
+    )?;
+
+    rec.log(
+        format!("{path}/center"),
+        &rerun::Points2D::new([center])
+            .with_class_ids([tag.id])
+            .with_keypoint_ids([CENTER_KEYPOINT_ID])
+            .with_radii([CENTER_RADIUS])
+            .with_labels([format!(
+                "{family}:{} (hamming: {}, margin: {:.1})",
+                tag.id, tag.hamming, tag.decision_margin
+            )]),
+    )?;
+
+    // Axes of the tag coordinate frame, the tag spans from -1 to 1 in both directions
+    let origin = project(&tag.quad.homography, 0.0, 0.0);
+    let x_axis = project(&tag.quad.homography, 1.0, 0.0);
+    let y_axis = project(&tag.quad.homography, 0.0, 1.0);
+
+    rec.log(
+        format!("{path}/axes"),
+        &rerun::Arrows2D::from_vectors([
+            [x_axis[0] - origin[0], x_axis[1] - origin[1]],
+            [y_axis[0] - origin[0], y_axis[1] - origin[1]],
+        ])
+        .with_origins([origin, origin])
+        .with_colors([
+            rerun::Color::from_rgb(255, 0, 0),
+            rerun::Color::from_rgb(0, 255, 0),
+        ])
+        .with_labels(["x", "y"]),
+    )?;
+
+    rec.log(
+        format!("{path}/decision_margin"),
+        &rerun::Scalars::new([tag.decision_margin as f64]),
+    )?;
+    rec.log(
+        format!("{path}/hamming"),
+        &rerun::Scalars::new([tag.hamming as f64]),
+    )?;
+
+    Ok(())
+}
+
+/// Generates a stable color for the tag, so the same tag has the same color in every frame.
+/// Every family gets its own band of hues, so tags of different families are easy to tell apart.
+pub fn tag_color(family: &str, id: u16) -> [u8; 3] {
+    let family_idx = FAMILY_NAMES
+        .iter()
+        .position(|name| *name == family)
+        .unwrap_or(FAMILY_NAMES.len());
+    let band = 360.0 / (FAMILY_NAMES.len() + 1) as f32;
+
+    let mut small_rng = rand::rngs::SmallRng::seed_from_u64(id as u64);
+    let hue = family_idx as f32 * band + small_rng.random_range(0.0..band * 0.8);
+    let saturation = small_rng.random_range(0.6..1.0);
+    let value = small_rng.random_range(0.7..1.0);
+
+    hsv_to_rgb(hue, saturation, value)
+}
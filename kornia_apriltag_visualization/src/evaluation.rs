@@ -0,0 +1,306 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use argh::FromArgs;
+use kornia_apriltag::{AprilTagDecoder, DecodeTagsConfig, decoder::Detection};
+use kornia_apriltag_visualization::{
+    corners::{distance, squared_corner_error},
+    family::{family_name, parse_families},
+    synthetic::{Distortion, PlacedTag, load_tag_images, render_scene},
+};
+use kornia_image::ImageSize;
+use rand::{SeedableRng, rngs::StdRng, seq::IndexedRandom};
+use serde::Serialize;
+
+#[derive(FromArgs)]
+/// Render the tags of apriltag-imgs into synthetic scenes and evaluate kornia-apriltag against
+/// the ground truth.
+struct Args {
+    /// path to the apriltag-imgs repository
+    #[argh(
+        option,
+        default = "PathBuf::from(\"./kornia_apriltag_visualization/apriltag_imgs\")"
+    )]
+    tags_dir: PathBuf,
+
+    /// tag family to evaluate, can be repeated (default: tag36h11)
+    #[argh(option)]
+    family: Vec<String>,
+
+    /// number of scenes rendered per distortion level
+    #[argh(option, default = "50")]
+    scenes: usize,
+
+    /// number of tags placed in every scene
+    #[argh(option, default = "4")]
+    tags_per_scene: usize,
+
+    /// seed of the random number generator
+    #[argh(option, default = "0")]
+    seed: u64,
+
+    /// directory where `evaluation.csv` and `evaluation.json` are written
+    #[argh(option, short = 'o', default = "PathBuf::from(\"evaluation_results\")")]
+    output: PathBuf,
+}
+
+const SCENE_SIZE: ImageSize = ImageSize {
+    width: 640,
+    height: 480,
+};
+
+/// Maximum number of tag images loaded per family.
+const MAX_TAG_IMAGES: usize = 1000;
+
+/// Metrics of a family at a single distortion level.
+#[derive(Debug, Serialize)]
+struct EvaluationRow {
+    family: &'static str,
+    distortion: &'static str,
+    level: usize,
+    tags: usize,
+    detected: usize,
+    wrong_id: usize,
+    false_positives: usize,
+    detection_rate: f32,
+    wrong_id_rate: f32,
+    /// Root mean square error of the corners of the correctly detected tags, in pixels
+    corner_rmse: Option<f32>,
+}
+
+/// Every distortion is evaluated at increasing levels of severity, while the others stay at
+/// their default values.
+fn distortion_levels() -> Vec<(&'static str, Vec<Distortion>)> {
+    let default = Distortion::default();
+
+    vec![
+        (
+            "scale",
+            [120.0, 60.0, 30.0, 20.0, 14.0]
+                .map(|tag_size| Distortion {
+                    tag_size,
+                    ..default
+                })
+                .to_vec(),
+        ),
+        (
+            "rotation",
+            [0.0f32, 15.0, 30.0, 45.0, 80.0]
+                .map(|degrees| Distortion {
+                    rotation: degrees.to_radians(),
+                    ..default
+                })
+                .to_vec(),
+        ),
+        (
+            "perspective",
+            [0.0, 0.05, 0.1, 0.2, 0.3]
+                .map(|perspective| Distortion {
+                    perspective,
+                    ..default
+                })
+                .to_vec(),
+        ),
+        (
+            "blur",
+            [0.0, 0.5, 1.0, 2.0, 3.0]
+                .map(|blur_sigma| Distortion {
+                    blur_sigma,
+                    ..default
+                })
+                .to_vec(),
+        ),
+        (
+            "noise",
+            [0.0, 5.0, 10.0, 20.0, 40.0]
+                .map(|noise_sigma| Distortion {
+                    noise_sigma,
+                    ..default
+                })
+                .to_vec(),
+        ),
+        (
+            "lighting",
+            [0.0, 0.25, 0.5, 0.75, 0.9]
+                .map(|lighting| Distortion {
+                    lighting,
+                    ..default
+                })
+                .to_vec(),
+        ),
+    ]
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = argh::from_env();
+
+    let families = parse_families(&args.family)?;
+
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let mut rows = Vec::new();
+
+    for family in families {
+        let tag_images = load_tag_images(&args.tags_dir, family.clone(), MAX_TAG_IMAGES)?;
+
+        let config = DecodeTagsConfig::new(vec![family.clone()]);
+        let mut decoder = AprilTagDecoder::new(config, SCENE_SIZE)?;
+
+        for (distortion_name, levels) in distortion_levels() {
+            for (level, distortion) in levels.iter().enumerate() {
+                let mut metrics = Metrics::default();
+
+                for _ in 0..args.scenes {
+                    let tags = tag_images
+                        .choose_multiple(&mut rng, args.tags_per_scene)
+                        .collect::<Vec<_>>();
+                    let scene = render_scene(SCENE_SIZE, &tags, distortion, &mut rng)?;
+
+                    let detections = decoder.decode(&scene.image)?;
+                    decoder.clear();
+
+                    metrics.add_scene(&scene.tags, &detections);
+                }
+
+                let row = metrics.into_row(family_name(&family), distortion_name, level);
+                println!(
+                    "{:>16} {:>12} level {}: detection rate {:.3}, wrong id rate {:.3}, false positives {}, corner rmse {}",
+                    row.family,
+                    row.distortion,
+                    row.level,
+                    row.detection_rate,
+                    row.wrong_id_rate,
+                    row.false_positives,
+                    row.corner_rmse
+                        .map_or_else(|| "-".to_string(), |rmse| format!("{rmse:.3} px")),
+                );
+                rows.push(row);
+            }
+        }
+    }
+
+    std::fs::create_dir_all(&args.output)?;
+    write_csv(&args.output.join("evaluation.csv"), &rows)?;
+    serde_json::to_writer_pretty(File::create(args.output.join("evaluation.json"))?, &rows)?;
+
+    println!("Results written to {}", args.output.display());
+
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    tags: usize,
+    detected: usize,
+    wrong_id: usize,
+    false_positives: usize,
+    squared_corner_error: f32,
+    corners: usize,
+}
+
+impl Metrics {
+    /// Matches the detections to the ground truth using the distance between the centers.
+    fn add_scene(&mut self, ground_truth: &[PlacedTag], detections: &[Detection]) {
+        let mut matched = vec![false; ground_truth.len()];
+        self.tags += ground_truth.len();
+
+        for detection in detections {
+            let center = [detection.center.x, detection.center.y];
+
+            let closest = ground_truth
+                .iter()
+                .enumerate()
+                .filter(|(_, tag)| distance(center, tag.center) < tag_radius(tag))
+                .min_by(|(_, a), (_, b)| {
+                    distance(center, a.center).total_cmp(&distance(center, b.center))
+                });
+
+            let Some((i, tag)) = closest else {
+                self.false_positives += 1;
+                continue;
+            };
+
+            if matched[i] {
+                // Duplicate detection of the same tag
+                self.false_positives += 1;
+                continue;
+            }
+
+            matched[i] = true;
+
+            if detection.id as usize != tag.id {
+                self.wrong_id += 1;
+                continue;
+            }
+
+            self.detected += 1;
+
+            let corners = detection.quad.corners.map(|corner| [corner.x, corner.y]);
+            self.squared_corner_error += squared_corner_error(&corners, &tag.corners);
+            self.corners += 4;
+        }
+    }
+
+    fn into_row(
+        self,
+        family: &'static str,
+        distortion: &'static str,
+        level: usize,
+    ) -> EvaluationRow {
+        let tags = self.tags.max(1) as f32;
+
+        EvaluationRow {
+            family,
+            distortion,
+            level,
+            tags: self.tags,
+            detected: self.detected,
+            wrong_id: self.wrong_id,
+            false_positives: self.false_positives,
+            detection_rate: self.detected as f32 / tags,
+            wrong_id_rate: self.wrong_id as f32 / tags,
+            corner_rmse: (self.corners > 0)
+                .then(|| (self.squared_corner_error / self.corners as f32).sqrt()),
+        }
+    }
+}
+
+/// Half of the shortest side of the tag, detections closer than this to the center belong to it.
+fn tag_radius(tag: &PlacedTag) -> f32 {
+    (0..4)
+        .map(|i| distance(tag.corners[i], tag.corners[(i + 1) % 4]))
+        .fold(f32::MAX, f32::min)
+        / 2.0
+}
+
+fn write_csv(path: &Path, rows: &[EvaluationRow]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+
+    writeln!(
+        file,
+        "family,distortion,level,tags,detected,wrong_id,false_positives,detection_rate,wrong_id_rate,corner_rmse"
+    )?;
+
+    for row in rows {
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{},{},{},{}",
+            row.family,
+            row.distortion,
+            row.level,
+            row.tags,
+            row.detected,
+            row.wrong_id,
+            row.false_positives,
+            row.detection_rate,
+            row.wrong_id_rate,
+            row.corner_rmse
+                .map(|rmse| rmse.to_string())
+                .unwrap_or_default(),
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,289 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use kornia_apriltag::decoder::Detection;
+use serde::Serialize;
+
+use crate::{
+    family::family_name,
+    pose::{TagPose, rotation_to_quaternion},
+};
+
+/// Columns of the CSV file, the pose columns are empty without a pose.
+const CSV_HEADER: [&str; 25] = [
+    "frame_idx",
+    "timestamp",
+    "source",
+    "family",
+    "id",
+    "hamming",
+    "decision_margin",
+    "center_x",
+    "center_y",
+    "corner0_x",
+    "corner0_y",
+    "corner1_x",
+    "corner1_y",
+    "corner2_x",
+    "corner2_y",
+    "corner3_x",
+    "corner3_y",
+    "tx",
+    "ty",
+    "tz",
+    "qx",
+    "qy",
+    "qz",
+    "qw",
+    "reprojection_error",
+];
+
+/// File format of the exported detections, chosen by the extension of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per frame and line, including the frames without any detection
+    JsonLines,
+    /// One row per detection
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") => Ok(Self::JsonLines),
+            Some("csv") => Ok(Self::Csv),
+            _ => Err(format!(
+                "Unknown export format of {}, expected a .jsonl or .csv file",
+                path.display()
+            )
+            .into()),
+        }
+    }
+}
+
+/// Detections of a single frame.
+#[derive(Debug, Serialize)]
+pub struct FrameRecord {
+    pub frame_idx: u64,
+    /// Capture time in seconds since the unix epoch
+    pub timestamp: f64,
+    /// Source of the frame, e.g. the path of the image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub detections: Vec<DetectionRecord>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DetectionRecord {
+    pub family: &'static str,
+    pub id: u16,
+    pub hamming: u8,
+    pub decision_margin: f32,
+    pub center: [f32; 2],
+    pub corners: [[f32; 2]; 4],
+    /// Only estimated with a camera calibration
+    pub pose: Option<TagPose>,
+}
+
+impl FrameRecord {
+    /// `poses` is either empty or has the pose of every detection.
+    pub fn new(
+        frame_idx: u64,
+        capture_time: SystemTime,
+        source: Option<String>,
+        detections: &[Detection],
+        poses: &[Option<TagPose>],
+    ) -> Self {
+        let detections = detections
+            .iter()
+            .enumerate()
+            .map(|(i, tag)| DetectionRecord {
+                family: family_name(&tag.tag_family_kind),
+                id: tag.id,
+                hamming: tag.hamming,
+                decision_margin: tag.decision_margin,
+                center: [tag.center.x, tag.center.y],
+                corners: tag.quad.corners.map(|corner| [corner.x, corner.y]),
+                pose: poses.get(i).copied().flatten(),
+            })
+            .collect();
+
+        Self {
+            frame_idx,
+            timestamp: capture_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            source,
+            detections,
+        }
+    }
+}
+
+enum ExportWriter {
+    JsonLines(BufWriter<File>),
+    Csv(csv::Writer<File>),
+}
+
+/// Writes the detections of every frame into a JSON Lines or CSV file.
+pub struct DetectionExporter {
+    path: PathBuf,
+    writer: ExportWriter,
+}
+
+impl DetectionExporter {
+    /// Creates the file, the format is chosen by its extension.
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.into();
+        let file = File::create(&path)?;
+
+        let writer = match ExportFormat::from_path(&path)? {
+            ExportFormat::JsonLines => ExportWriter::JsonLines(BufWriter::new(file)),
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(file);
+                writer.write_record(CSV_HEADER)?;
+                ExportWriter::Csv(writer)
+            }
+        };
+
+        Ok(Self { path, writer })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&mut self, record: &FrameRecord) -> Result<(), Box<dyn std::error::Error>> {
+        match &mut self.writer {
+            ExportWriter::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writeln!(writer)?;
+            }
+            ExportWriter::Csv(writer) => {
+                for tag in &record.detections {
+                    let mut fields = vec![
+                        record.frame_idx.to_string(),
+                        record.timestamp.to_string(),
+                        record.source.clone().unwrap_or_default(),
+                        tag.family.to_string(),
+                        tag.id.to_string(),
+                        tag.hamming.to_string(),
+                        tag.decision_margin.to_string(),
+                        tag.center[0].to_string(),
+                        tag.center[1].to_string(),
+                    ];
+                    fields.extend(tag.corners.iter().flatten().map(|v| v.to_string()));
+
+                    if let Some(pose) = &tag.pose {
+                        fields.extend(pose.translation.map(|v| v.to_string()));
+                        fields
+                            .extend(rotation_to_quaternion(&pose.rotation).map(|v| v.to_string()));
+                        fields.push(pose.reprojection_error.to_string());
+                    }
+                    fields.resize(CSV_HEADER.len(), String::new());
+
+                    writer.write_record(&fields)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match &mut self.writer {
+            ExportWriter::JsonLines(writer) => writer.flush()?,
+            ExportWriter::Csv(writer) => writer.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of columns of the CSV header.
+    const CSV_COLUMNS: usize = 25;
+
+    fn detection(pose: Option<TagPose>) -> DetectionRecord {
+        DetectionRecord {
+            family: "tag36h11",
+            id: 7,
+            hamming: 1,
+            decision_margin: 42.5,
+            center: [10.0, 20.0],
+            corners: [[0.0, 0.0], [20.0, 0.0], [20.0, 40.0], [0.0, 40.0]],
+            pose,
+        }
+    }
+
+    fn fields(row: &csv::StringRecord, range: std::ops::Range<usize>) -> Vec<&str> {
+        range.map(|i| &row[i]).collect()
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let pose = TagPose {
+            rotation: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            translation: [0.1, -0.2, 1.5],
+            reprojection_error: 0.25,
+            tag_size: 0.1,
+        };
+        let source = "data/a,b \"c\"\nd.jpg";
+        let records = [
+            FrameRecord {
+                frame_idx: 0,
+                timestamp: 1.5,
+                source: Some(source.to_string()),
+                detections: vec![detection(Some(pose))],
+            },
+            FrameRecord {
+                frame_idx: 1,
+                timestamp: 2.5,
+                source: None,
+                detections: vec![detection(None)],
+            },
+        ];
+
+        let path = std::env::temp_dir().join(format!("export_{}.csv", std::process::id()));
+        let mut exporter = DetectionExporter::create(&path).unwrap();
+        for record in &records {
+            exporter.write(record).unwrap();
+        }
+        exporter.flush().unwrap();
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        assert_eq!(reader.headers().unwrap().len(), CSV_COLUMNS);
+        let rows = reader.records().collect::<Result<Vec<_>, _>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        for row in &rows {
+            assert_eq!(row.len(), CSV_COLUMNS);
+            assert_eq!(&row[3], "tag36h11");
+            assert_eq!(&row[4], "7");
+            assert_eq!(
+                fields(row, 9..17),
+                ["0", "0", "20", "0", "20", "40", "0", "40"]
+            );
+        }
+
+        // With a pose
+        assert_eq!(&rows[0][0], "0");
+        assert_eq!(&rows[0][2], source);
+        assert_eq!(fields(&rows[0], 17..20), ["0.1", "-0.2", "1.5"]);
+        assert_eq!(fields(&rows[0], 20..24), ["0", "0", "0", "1"]);
+        assert_eq!(&rows[0][24], "0.25");
+
+        // Without a pose the pose columns are empty
+        assert_eq!(&rows[1][0], "1");
+        assert_eq!(&rows[1][2], "");
+        assert!(rows[1].iter().skip(17).all(|field| field.is_empty()));
+    }
+}
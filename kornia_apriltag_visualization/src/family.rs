@@ -0,0 +1,87 @@
+use kornia_apriltag::family::TagFamilyKind;
+
+/// Names of the tag families, same as the directories of `apriltag_imgs`.
+pub const FAMILY_NAMES: [&str; 9] = [
+    "tag16h5",
+    "tag25h9",
+    "tag36h10",
+    "tag36h11",
+    "tagCircle21h7",
+    "tagCircle49h12",
+    "tagCustom48h12",
+    "tagStandard41h12",
+    "tagStandard52h13",
+];
+
+pub fn family_name(kind: &TagFamilyKind) -> &'static str {
+    match kind {
+        TagFamilyKind::Tag16H5 => "tag16h5",
+        TagFamilyKind::Tag25H9 => "tag25h9",
+        TagFamilyKind::Tag36H10 => "tag36h10",
+        TagFamilyKind::Tag36H11 => "tag36h11",
+        TagFamilyKind::TagCircle21H7 => "tagCircle21h7",
+        TagFamilyKind::TagCircle49H12 => "tagCircle49h12",
+        TagFamilyKind::TagCustom48H12 => "tagCustom48h12",
+        TagFamilyKind::TagStandard41H12 => "tagStandard41h12",
+        TagFamilyKind::TagStandard52H13 => "tagStandard52h13",
+        #[allow(unreachable_patterns)]
+        _ => "custom",
+    }
+}
+
+/// Parses the tag family from its name, see [`FAMILY_NAMES`].
+pub fn parse_family(name: &str) -> Option<TagFamilyKind> {
+    let kind = match name {
+        "tag16h5" => TagFamilyKind::Tag16H5,
+        "tag25h9" => TagFamilyKind::Tag25H9,
+        "tag36h10" => TagFamilyKind::Tag36H10,
+        "tag36h11" => TagFamilyKind::Tag36H11,
+        "tagCircle21h7" => TagFamilyKind::TagCircle21H7,
+        "tagCircle49h12" => TagFamilyKind::TagCircle49H12,
+        "tagCustom48h12" => TagFamilyKind::TagCustom48H12,
+        "tagStandard41h12" => TagFamilyKind::TagStandard41H12,
+        "tagStandard52h13" => TagFamilyKind::TagStandard52H13,
+        _ => return None,
+    };
+
+    Some(kind)
+}
+
+/// Parses the families passed on the command line, tag36h11 if none were passed.
+pub fn parse_families(names: &[String]) -> Result<Vec<TagFamilyKind>, Box<dyn std::error::Error>> {
+    if names.is_empty() {
+        return Ok(vec![TagFamilyKind::Tag36H11]);
+    }
+
+    names
+        .iter()
+        .map(|name| {
+            parse_family(name).ok_or_else(|| {
+                format!(
+                    "Unknown tag family {name}, expected one of {}",
+                    FAMILY_NAMES.join(", ")
+                )
+                .into()
+            })
+        })
+        .collect()
+}
+
+/// Prefix of the image files of the family in `apriltag_imgs`, e.g. `tag36_11_00000.png`.
+pub fn family_image_prefix(kind: &TagFamilyKind) -> Option<&'static str> {
+    let prefix = match kind {
+        TagFamilyKind::Tag16H5 => "tag16_05",
+        TagFamilyKind::Tag25H9 => "tag25_09",
+        TagFamilyKind::Tag36H10 => "tag36_10",
+        TagFamilyKind::Tag36H11 => "tag36_11",
+        TagFamilyKind::TagCircle21H7 => "tag21_07",
+        TagFamilyKind::TagCircle49H12 => "tag49_12",
+        TagFamilyKind::TagCustom48H12 => "tag48_12",
+        TagFamilyKind::TagStandard41H12 => "tag41_12",
+        TagFamilyKind::TagStandard52H13 => "tag52_13",
+        #[allow(unreachable_patterns)]
+        _ => return None,
+    };
+
+    Some(prefix)
+}
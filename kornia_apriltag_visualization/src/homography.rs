@@ -0,0 +1,69 @@
+use kornia_apriltag::utils::Point2d;
+
+/// Corners of a quad in the tag coordinate frame, which spans from -1 to 1 in both directions. The
+/// order is the same as `fit_quads` returns them.
+pub const TAG_CORNERS: [[f64; 2]; 4] = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+
+/// Computes the homography mapping the tag coordinate frame onto the corners of a quad, see
+/// [`TAG_CORNERS`].
+///
+/// Returns `None` if the corners are degenerate.
+pub fn homography_from_corners(corners: &[Point2d<f32>; 4]) -> Option<[f32; 9]> {
+    let dst = corners.map(|corner| [corner.x as f64, corner.y as f64]);
+    homography_from_points(&TAG_CORNERS, &dst)
+}
+
+/// Computes the homography mapping the 4 `src` points onto the 4 `dst` points by solving the
+/// direct linear transform with `h[8]` fixed to 1.
+pub fn homography_from_points(src: &[[f64; 2]; 4], dst: &[[f64; 2]; 4]) -> Option<[f32; 9]> {
+    let mut a = [[0.0f64; 9]; 8];
+
+    for (i, (s, d)) in src.iter().zip(dst).enumerate() {
+        let [x, y] = *s;
+        let [u, v] = *d;
+
+        a[i * 2] = [x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u, u];
+        a[i * 2 + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v, v];
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..8 {
+        let pivot = (col..8).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+
+        a.swap(col, pivot);
+
+        for row in 0..8 {
+            if row == col {
+                continue;
+            }
+
+            let pivot_row = a[col];
+            let factor = a[row][col] / pivot_row[col];
+            for (value, pivot) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot;
+            }
+        }
+    }
+
+    let mut h = [1.0f32; 9];
+    for (i, row) in a.iter().enumerate() {
+        h[i] = (row[8] / row[i]) as f32;
+    }
+
+    Some(h)
+}
+
+/// Maps a point through the homography.
+pub fn project(homography: &[f32; 9], x: f32, y: f32) -> [f32; 2] {
+    let h = homography;
+    let z = h[6] * x + h[7] * y + h[8];
+
+    [
+        (h[0] * x + h[1] * y + h[2]) / z,
+        (h[3] * x + h[4] * y + h[5]) / z,
+    ]
+}
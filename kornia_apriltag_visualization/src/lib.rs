@@ -0,0 +1,25 @@
+pub mod allocations;
+pub mod annotate;
+pub mod calibration;
+pub mod corners;
+pub mod decoding;
+pub mod detection;
+pub mod export;
+pub mod family;
+pub mod homography;
+pub mod palette;
+pub mod pipeline;
+pub mod pose;
+pub mod quad;
+pub mod queue;
+pub mod refine;
+pub mod rejection;
+pub mod roi;
+pub mod segmentation;
+pub mod source;
+pub mod supervisor;
+pub mod synthetic;
+pub mod timeline;
+pub mod tracking;
+pub mod undistort;
+pub mod video;
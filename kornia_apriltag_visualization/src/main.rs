@@ -1,46 +1,185 @@
 use std::{
-    collections::HashMap,
+    path::{Path, PathBuf},
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
+    time::{Duration, Instant, SystemTime},
 };
 
-use kornia_apriltag::{
-    DecodeTagsConfig, decoder::GrayModelPair, family::TagFamilyKind, threshold::TileMinMax,
-    union_find::UnionFind, utils::Pixel,
+use argh::FromArgs;
+use device_supervisor::SupervisorConfig;
+use kornia_apriltag::{DecodeTagsConfig, family::TagFamilyKind};
+use kornia_image::{
+    Image, ImageSize,
+    allocator::{CpuAllocator, ImageAllocator},
 };
-use kornia_image::{Image, ImageSize, allocator::CpuAllocator};
-use kornia_io::{fps_counter::FpsCounter, stream::V4L2CameraConfig};
 
-use crate::{
+use kornia_apriltag_visualization::{
+    allocations::{AllocationStats, measure_allocations},
+    annotate::{RenderOptions, Renderer, copy_to_rgb},
+    calibration::CameraCalibration,
+    decoding::{debug_decode_quad, log_quad_decode},
+    detection::DetectionLogger,
+    export::{DetectionExporter, FrameRecord},
+    family::parse_families,
+    palette::Palette,
+    pipeline::Pipeline,
+    pose::{TagPose, estimate_tag_pose},
     quad::debug_quad_fitting,
-    segmentation::{debug_connected_components, debug_gradient_clusters},
+    queue::{DropPolicy, FrameQueue, QueueProducer},
+    refine::corner_corrections,
+    rejection::{find_rejected_clusters, log_rejected_clusters, log_rejection_annotation_context},
+    roi::{Roi, load_roi_mask},
+    segmentation::{Segmentation, debug_connected_components, debug_gradient_clusters},
+    source::{FrameHandler, FrameRef, FrameSource, PixelFormat},
+    supervisor::SupervisedSource,
+    timeline::set_frame_time,
+    tracking::{Tracker, TrackerConfig, log_tracks},
+    undistort::{UndistortMode, Undistorter},
+    video::AnnotatedVideoWriter,
 };
 
-mod quad;
-mod segmentation;
+#[cfg(feature = "dhat-heap")]
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
 
-// TODO: add CLI arguments
-const CAMERA_ID: u32 = 0;
-const FPS: u32 = 30;
+#[derive(FromArgs)]
+/// Capture frames from a webcam and visualize every step of kornia-apriltag decoding in rerun.
+struct Args {
+    /// the camera id to use, can be repeated to process several cameras in parallel (default: 0)
+    #[argh(option, short = 'c')]
+    camera_id: Vec<u32>,
+
+    /// the frames per second to record
+    #[argh(option, short = 'f', default = "30")]
+    fps: u32,
+
+    /// JPEG image to replay in a loop instead of the camera, can be repeated
+    #[argh(option, short = 'i')]
+    image: Vec<PathBuf>,
+
+    /// comma separated JPEG images replayed in a loop as an additional source, can be repeated
+    #[argh(option)]
+    image_source: Vec<String>,
+
+    /// stop after processing this many frames
+    #[argh(option, short = 'n')]
+    max_frames: Option<u64>,
+
+    /// tag family to detect, can be repeated (default: tag36h11)
+    #[argh(option)]
+    family: Vec<String>,
+
+    /// log the rectified patch, sampled bits and gray models of every quad
+    #[argh(switch)]
+    debug_decoding: bool,
+
+    /// log the gradient clusters rejected by quad fitting along with the reason
+    #[argh(switch)]
+    debug_rejected: bool,
+
+    /// color the clusters of the debug images by their bounding box only, instead of keeping the
+    /// color of the overlapping cluster of the previous frame
+    #[argh(switch)]
+    per_frame_colors: bool,
+
+    /// camera calibration (JSON or YAML) used to estimate the 3D pose of the tags, either one
+    /// for all sources or one per source in the order of the sources
+    #[argh(option)]
+    calibration: Vec<PathBuf>,
+
+    /// edge length of the black border of the tags in meters
+    #[argh(option, default = "0.1")]
+    tag_size: f64,
+
+    /// remove the lens distortion of the calibration from the whole image or only from the
+    /// detected corners (image, corners)
+    #[argh(option)]
+    undistort: Option<UndistortMode>,
+
+    /// search the quads on the frame downscaled by this factor, then refine them on the full
+    /// resolution frame
+    #[argh(option, default = "1")]
+    decimate: usize,
+
+    /// re-fit the quad edges against the image gradients for sub-pixel accurate corners, always
+    /// on with --decimate
+    #[argh(switch)]
+    refine_edges: bool,
+
+    /// follow the tags across frames and smooth their corners
+    #[argh(switch)]
+    track: bool,
+
+    /// only search the regions where the tracked tags are predicted, implies --track
+    #[argh(switch)]
+    roi: bool,
+
+    /// PNG mask whose non-zero pixels mark the regions to search for tags in
+    #[argh(option)]
+    roi_mask: Option<PathBuf>,
+
+    /// scan the whole frame every this many frames to pick up new tags when searching regions
+    #[argh(option, default = "10")]
+    full_scan_interval: u64,
+
+    /// request GRAY8 frames from the camera and log grayscale views only, which skips the color
+    /// conversion
+    #[argh(switch)]
+    no_color: bool,
+
+    /// write the detections of every frame to this .jsonl or .csv file, can be repeated. With
+    /// several sources, the name of the source is appended to the file name
+    #[argh(option)]
+    export: Vec<PathBuf>,
+
+    /// write the frames with the detected tags drawn on top into this H.264 video of --fps
+    /// frames per second, placed by their capture time. With several sources, the name of the
+    /// source is appended to the file name
+    #[argh(option)]
+    video: Option<PathBuf>,
+
+    /// number of captured frames waiting to be processed before frames are dropped
+    #[argh(option, default = "2")]
+    queue_size: usize,
+
+    /// frame dropped when the queue is full, the oldest queued one or the newest captured one
+    /// (oldest, newest)
+    #[argh(option, default = "DropPolicy::Oldest")]
+    drop_policy: DropPolicy,
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let rec = rerun::RecordingStreamBuilder::new("Kornia-apriltag visualization").spawn()?;
+    #[cfg(feature = "dhat-heap")]
+    let _profiler = dhat::Profiler::new_heap();
 
-    // Create and start the webcam pipeline
-    let mut webcam = V4L2CameraConfig::new()
-        .with_camera_id(CAMERA_ID)
-        .with_fps(FPS)
-        .with_size(ImageSize {
-            width: 640,
-            height: 480,
-        })
-        .build()?;
-    webcam.start()?;
+    let args: Args = argh::from_env();
+    let families = parse_families(&args.family)?;
+    let sources = parse_sources(&args);
+
+    let calibrations = args
+        .calibration
+        .iter()
+        .map(CameraCalibration::load)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if calibrations.len() > 1 && calibrations.len() != sources.len() {
+        return Err(format!(
+            "Got {} calibrations for {} sources, pass either one or one per source",
+            calibrations.len(),
+            sources.len()
+        )
+        .into());
+    }
+
+    if args.undistort.is_some() && calibrations.is_empty() {
+        return Err("--undistort requires a --calibration".into());
+    }
+
+    let rec = rerun::RecordingStreamBuilder::new("Kornia-apriltag visualization").spawn()?;
 
     let cancel_token = Arc::new(AtomicBool::new(false));
-    let mut fps_counter = FpsCounter::new();
 
     ctrlc::set_handler({
         let cancel_token = cancel_token.clone();
@@ -50,160 +189,1068 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     })?;
 
-    println!("Waiting for 500 ms!");
-    std::thread::sleep(std::time::Duration::from_millis(500));
-
-    let first_frame = webcam
-        .grab_rgb8()?
-        .ok_or("Failed to fetch the initial frame, to get info about image size")?;
-
-    // Preallocated Image buffers
-    let mut grayscale_image = Image::from_size_val(first_frame.size(), 0u8, CpuAllocator)?;
-    let mut binary_image = Image::from_size_val(first_frame.size(), Pixel::Skip, CpuAllocator)?;
-    let mut segmentation_image = Image::from_size_val(first_frame.size(), 0u8, CpuAllocator)?;
-    let mut cluster_image = Image::from_size_val(first_frame.size(), 0u8, CpuAllocator)?;
-    let mut quads_image = Image::from_size_val(first_frame.size(), 0u8, CpuAllocator)?;
-    // let mut detections_image = Image::from_size_val(first_frame.size(), 0u8, CpuAllocator)?;
-
-    // Preallocated extras
-    let mut tile_min_max = TileMinMax::new(binary_image.size(), 4);
-    let mut uf = UnionFind::new(first_frame.width() * first_frame.height());
-    let mut clusters = HashMap::new();
-    let mut gray_model_pair = GrayModelPair::new();
-    let mut config = DecodeTagsConfig::new(vec![TagFamilyKind::Tag36H11]);
-
-    drop(first_frame);
-
-    while !cancel_token.load(Ordering::SeqCst) {
-        let Some(img) = webcam.grab_rgb8()? else {
-            continue;
+    // Every source runs its own pipeline on its own thread, logging under its own entity path
+    let errors = std::thread::scope(|scope| {
+        let handles = sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                let calibration = calibrations.get(i).or(calibrations.first()).cloned();
+                let (args, families, cancel_token) = (&args, &families, &cancel_token);
+                let rec = rec.clone();
+
+                // Keep the entity paths of a single source as they were
+                let prefix = if sources.len() > 1 {
+                    source.name()
+                } else {
+                    String::new()
+                };
+
+                scope.spawn(move || {
+                    run_source(
+                        args,
+                        rec,
+                        source,
+                        prefix,
+                        calibration,
+                        families,
+                        cancel_token,
+                    )
+                    .map_err(|err| format!("{}: {err}", source.name()))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .filter_map(|handle| match handle.join() {
+                Ok(result) => result.err(),
+                Err(_) => Some("Source thread panicked".to_string()),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+
+    println!("Finished recording. Closing app.");
+
+    Ok(())
+}
+
+/// Source of frames passed on the command line.
+enum SourceArg {
+    Camera(u32),
+    Images(Vec<PathBuf>),
+}
+
+impl SourceArg {
+    fn name(&self) -> String {
+        match self {
+            SourceArg::Camera(camera_id) => format!("camera_{camera_id}"),
+            SourceArg::Images(paths) => paths
+                .first()
+                .and_then(|path| path.file_stem())
+                .map(|stem| format!("images_{}", stem.to_string_lossy()))
+                .unwrap_or_else(|| "images".to_string()),
+        }
+    }
+}
+
+fn parse_sources(args: &Args) -> Vec<SourceArg> {
+    let mut sources = args
+        .camera_id
+        .iter()
+        .map(|&camera_id| SourceArg::Camera(camera_id))
+        .collect::<Vec<_>>();
+
+    if !args.image.is_empty() {
+        sources.push(SourceArg::Images(args.image.clone()));
+    }
+
+    for images in &args.image_source {
+        sources.push(SourceArg::Images(
+            images.split(',').map(PathBuf::from).collect(),
+        ));
+    }
+
+    if sources.is_empty() {
+        sources.push(SourceArg::Camera(0));
+    }
+
+    sources
+}
+
+/// Captures the frames of the source on one thread and processes them on another, until the
+/// cancel token is set.
+fn run_source(
+    args: &Args,
+    rec: rerun::RecordingStream,
+    source: &SourceArg,
+    prefix: String,
+    calibration: Option<CameraCalibration>,
+    families: &[TagFamilyKind],
+    cancel_token: &AtomicBool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let queue = FrameQueue::new(args.queue_size, args.drop_policy);
+
+    std::thread::scope(|scope| {
+        let capture = scope.spawn(|| {
+            let result = capture_frames(args, &rec, source, &prefix, &queue, cancel_token)
+                .map_err(|err| err.to_string());
+
+            // Wake up the processing thread, it stops once the queued frames are processed
+            queue.close();
+            result
+        });
+
+        // Take the frames out of the queue and run the visualizer on them, logging the dropped
+        // frames and the latency from the capture to the end of the processing
+        let process = || -> Result<(), Box<dyn std::error::Error>> {
+            let name = source.name();
+
+            // The visualizer is created for the size of the first frame
+            let Some(first) = queue.pop() else {
+                return Ok(());
+            };
+            let mut visualizer = Visualizer::new(
+                args,
+                rec.clone(),
+                prefix.clone(),
+                calibration,
+                families,
+                first.frame.as_frame_ref().size(),
+            )?;
+
+            let mut fps_meter = FpsMeter::new();
+            let mut last_seq = None;
+            let mut next = Some(first);
+
+            while let Some(queued) = next.take().or_else(|| queue.pop()) {
+                if cancel_token.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                if args
+                    .max_frames
+                    .is_some_and(|max_frames| visualizer.views.frame_idx >= max_frames)
+                {
+                    break;
+                }
+
+                visualizer.capture_time = Some((queued.captured_at, queued.capture_time));
+                visualizer.handle(queued.frame.as_frame_ref())?;
+
+                let latency = queued.captured_at.elapsed();
+                let dropped = last_seq.map_or(0, |last_seq| queued.seq - last_seq - 1);
+                last_seq = Some(queued.seq);
+                queue.recycle(queued.frame);
+
+                rec.log(
+                    entity_path(&prefix, "Queue/latency_ms"),
+                    &rerun::Scalars::new([latency.as_secs_f64() * 1000.0]),
+                )?;
+                rec.log(
+                    entity_path(&prefix, "Queue/dropped_frames"),
+                    &rerun::Scalars::new([dropped as f64]),
+                )?;
+
+                if let Some(fps) = fps_meter.update() {
+                    println!(
+                        "{name}: {fps:.1} fps, {} frames dropped so far",
+                        queue.dropped()
+                    );
+                    rec.log(entity_path(&prefix, "FPS"), &rerun::Scalars::new([fps]))?;
+                }
+            }
+
+            for exporter in &mut visualizer.views.exporters {
+                exporter.flush()?;
+                println!(
+                    "{name}: exported the detections to {}",
+                    exporter.path().display()
+                );
+            }
+
+            if let Some(video) = &mut visualizer.views.video {
+                video.close()?;
+            }
+
+            let views = &visualizer.views;
+            if views.frame_idx > 1 && cfg!(feature = "dhat-heap") {
+                println!(
+                    "{name}: steady state allocations: {} blocks, {} bytes in {} frames",
+                    views.steady_state_allocations.blocks,
+                    views.steady_state_allocations.bytes,
+                    views.frame_idx - 1
+                );
+            }
+
+            Ok(())
         };
+        let result = process();
 
-        // TEMP FIX: to avoid crash due to gstreamer
-        let img = Image::from_size_slice(img.size(), img.as_slice(), CpuAllocator)?;
+        // Stop the capture thread, e.g. after an error or the last frame
+        queue.close();
 
-        rec.log(
-            "Original Frame",
-            &rerun::Image::from_elements(img.as_slice(), img.size().into(), rerun::ColorModel::RGB),
-        )?;
+        capture.join().map_err(|_| "Capture thread panicked")??;
 
-        rec.log(
-            "Detected Tags",
-            &rerun::Image::from_elements(img.as_slice(), img.size().into(), rerun::ColorModel::RGB),
-        )?;
+        result
+    })
+}
+
+/// Grabs the frames of the source and pushes them into the queue.
+fn capture_frames(
+    args: &Args,
+    rec: &rerun::RecordingStream,
+    source: &SourceArg,
+    prefix: &str,
+    queue: &FrameQueue,
+    cancel_token: &AtomicBool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format = if args.no_color {
+        PixelFormat::Gray8
+    } else {
+        PixelFormat::Rgb8
+    };
+
+    // The frame source is opened by the supervisor, and reopened whenever the camera is lost
+    let open = || -> Result<FrameSource, Box<dyn std::error::Error>> {
+        match source {
+            SourceArg::Camera(camera_id) => {
+                let webcam = FrameSource::camera(
+                    *camera_id,
+                    args.fps,
+                    ImageSize {
+                        width: 640,
+                        height: 480,
+                    },
+                    format,
+                )?;
+
+                println!("Waiting for 500 ms!");
+                std::thread::sleep(std::time::Duration::from_millis(500));
+
+                Ok(webcam)
+            }
+            SourceArg::Images(paths) => FrameSource::images(paths, format),
+        }
+    };
+
+    let mut frame_source = SupervisedSource::new(
+        open,
+        SupervisorConfig::default(),
+        rec.clone(),
+        entity_path(prefix, "Camera Status"),
+    );
+
+    // The images are always available, replay them at the frame rate of a camera
+    let frame_interval = match source {
+        SourceArg::Camera(_) => Duration::ZERO,
+        SourceArg::Images(_) => Duration::from_secs_f64(1.0 / args.fps.max(1) as f64),
+    };
+
+    let mut producer = QueueProducer::new(queue);
+    let mut next_frame = Instant::now();
+
+    while !cancel_token.load(Ordering::SeqCst) && !queue.is_closed() {
+        let now = Instant::now();
+        if now < next_frame {
+            std::thread::sleep(next_frame - now);
+        }
+
+        if frame_source.grab_with(&mut producer)? {
+            next_frame += frame_interval;
+        } else {
+            // Wait a bit for the next frame instead of busy looping
+            std::thread::sleep(Duration::from_millis(1));
+            next_frame = Instant::now();
+        }
+    }
+
+    frame_source.close()
+}
+
+/// Frames per second of a single source, computed once per second.
+struct FpsMeter {
+    start: Instant,
+    frames: u32,
+}
+
+impl FpsMeter {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    /// Counts a frame, returns the frame rate once a second has passed.
+    fn update(&mut self) -> Option<f64> {
+        self.frames += 1;
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed < 1.0 {
+            return None;
+        }
+
+        let fps = self.frames as f64 / elapsed;
+        self.start = Instant::now();
+        self.frames = 0;
+
+        Some(fps)
+    }
+}
+
+/// Processes the frames handed over by the [`FrameSource`] and logs every stage.
+struct Visualizer<'a> {
+    args: &'a Args,
+    rec: rerun::RecordingStream,
+    /// Entity path all the views of the source are logged under
+    prefix: String,
+    calibration: Option<CameraCalibration>,
+    /// Remap tables, only needed when the whole image is undistorted
+    undistorter: Option<Undistorter>,
+    undistorted_image: Image<u8, 3, CpuAllocator>,
+    /// Grayscale frame swapped out of the pipeline while it's logged
+    gray_frame: Image<u8, 1, CpuAllocator>,
+    /// Capture time of the next handled frame, the time it's handled if unknown
+    capture_time: Option<(Instant, SystemTime)>,
+    views: Views,
+}
+
+/// Pipeline and the state of the views logged from it.
+struct Views {
+    pipeline: Pipeline,
+    components: Segmentation,
+    clusters: Segmentation,
+    quads_image: Image<u8, 3, CpuAllocator>,
+    /// Colors of the connected components, gradient clusters and quads
+    component_palette: Palette,
+    cluster_palette: Palette,
+    quad_palette: Palette,
+    detection_logger: DetectionLogger,
+    poses: Vec<Option<TagPose>>,
+    exporters: Vec<DetectionExporter>,
+    capture_time: SystemTime,
+    renderer: Renderer,
+    /// Frame with the detected tags drawn on top, only needed for the video
+    annotated_image: Image<u8, 3, CpuAllocator>,
+    video: Option<AnnotatedVideoWriter>,
+    mask_rois: Vec<Roi>,
+    rois: Vec<Roi>,
+    tracker: Tracker,
+    last_frame_time: Option<Instant>,
+    /// Time since the previous frame, used to predict the motion of the tracked tags
+    dt: f32,
+    /// Index of the current frame, only advanced once the frame is fully logged
+    frame_idx: u64,
+    /// Allocations of all the frames except the first one, which fills the preallocated buffers
+    steady_state_allocations: AllocationStats,
+}
+
+impl<'a> Visualizer<'a> {
+    fn new(
+        args: &'a Args,
+        rec: rerun::RecordingStream,
+        prefix: String,
+        calibration: Option<CameraCalibration>,
+        families: &[TagFamilyKind],
+        frame_size: ImageSize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = DecodeTagsConfig::new(families.to_vec());
+        let mut pipeline = Pipeline::with_decimation(frame_size, config, args.decimate)?;
+        pipeline.refine = args.refine_edges;
+        let detection_size = pipeline.detection_size();
+
+        let mut detection_logger =
+            DetectionLogger::new(entity_path(&prefix, "Detected Tags"), families)
+                .with_counts_path(entity_path(&prefix, "Detection Counts"));
+
+        if let Some(calibration) = &calibration {
+            if calibration.width as usize != frame_size.width
+                || calibration.height as usize != frame_size.height
+            {
+                println!(
+                    "Calibration is for {}x{} but the frames are {}x{}",
+                    calibration.width, calibration.height, frame_size.width, frame_size.height
+                );
+            }
+
+            // The tag poses are in the camera frame, so the camera sits at the origin of the world
+            rec.log_static(
+                entity_path(&prefix, "world"),
+                &rerun::ViewCoordinates::RDF(),
+            )?;
+            calibration.log(&rec, &entity_path(&prefix, "world/camera"))?;
+            detection_logger = detection_logger.with_pose_path(entity_path(&prefix, "world/tags"));
+        }
+
+        let undistorter = calibration
+            .as_ref()
+            .filter(|_| args.undistort == Some(UndistortMode::Image))
+            .map(|calibration| Undistorter::new(calibration, frame_size));
+
+        if args.debug_rejected {
+            log_rejection_annotation_context(&rec, &entity_path(&prefix, "Rejected Clusters"))?;
+        }
+
+        let exporters = args
+            .export
+            .iter()
+            .map(|path| DetectionExporter::create(source_file_path(path, &prefix)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let video = args
+            .video
+            .as_ref()
+            .map(|path| {
+                AnnotatedVideoWriter::create(source_file_path(path, &prefix), args.fps, frame_size)
+            })
+            .transpose()?;
+
+        // The poses were estimated from the undistorted corners, but drawn onto the distorted
+        // frame with --undistort corners
+        let mut renderer = Renderer::new(RenderOptions::default());
+        if let Some(calibration) = &calibration {
+            renderer = renderer.with_calibration(
+                calibration.clone(),
+                args.undistort == Some(UndistortMode::Corners),
+            );
+        }
 
-        // Convert to grayscale
-        kornia_imgproc::color::gray_from_rgb_u8(&img, &mut grayscale_image)?;
+        let mask_rois = args
+            .roi_mask
+            .as_ref()
+            .map(load_roi_mask)
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            args,
+            rec,
+            prefix,
+            calibration,
+            undistorter,
+            // Preallocated Image buffers
+            undistorted_image: Image::from_size_val(frame_size, 0u8, CpuAllocator)?,
+            gray_frame: Image::from_size_val(frame_size, 0u8, CpuAllocator)?,
+            capture_time: None,
+            views: Views {
+                pipeline,
+                components: Segmentation::new(detection_size, "component"),
+                clusters: Segmentation::new(detection_size, "cluster"),
+                quads_image: Image::from_size_val(frame_size, 0u8, CpuAllocator)?,
+                component_palette: Palette::new(!args.per_frame_colors),
+                cluster_palette: Palette::new(!args.per_frame_colors),
+                quad_palette: Palette::new(!args.per_frame_colors),
+                detection_logger,
+                poses: Vec::new(),
+                exporters,
+                capture_time: SystemTime::now(),
+                renderer,
+                annotated_image: Image::from_size_val(frame_size, 0u8, CpuAllocator)?,
+                video,
+                mask_rois,
+                rois: Vec::new(),
+                tracker: Tracker::new(TrackerConfig::default()),
+                last_frame_time: None,
+                dt: 0.0,
+                frame_idx: 0,
+                steady_state_allocations: AllocationStats::default(),
+            },
+        })
+    }
+}
+
+impl FrameHandler for Visualizer<'_> {
+    fn handle<A: ImageAllocator>(
+        &mut self,
+        frame: FrameRef<'_, A>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let args = self.args;
+        let rec = &self.rec;
+        let prefix = self.prefix.as_str();
+        let views = &mut self.views;
+
+        let (captured_at, capture_time) = self
+            .capture_time
+            .take()
+            .unwrap_or_else(|| (Instant::now(), SystemTime::now()));
+        views.begin_frame(rec, args, captured_at, capture_time);
+
+        match frame {
+            FrameRef::Rgb(img) => {
+                log_image(rec, &entity_path(prefix, "Original Frame"), img)?;
+
+                // The queued frame is fed into the pipeline without copying it again
+                match &self.undistorter {
+                    Some(undistorter) => {
+                        undistorter.undistort_image(img, &mut self.undistorted_image)?;
+                        log_image(
+                            rec,
+                            &entity_path(prefix, "Undistorted Frame"),
+                            &self.undistorted_image,
+                        )?;
+
+                        views.detect(rec, prefix, args, |pipeline, rois| match rois {
+                            Some(rois) => pipeline.process_rois(&self.undistorted_image, rois),
+                            None => pipeline.process(&self.undistorted_image),
+                        })?;
+                        views.log_decoding(rec, prefix, args)?;
+                        views.log(
+                            rec,
+                            prefix,
+                            args,
+                            self.calibration.as_ref(),
+                            &self.undistorted_image,
+                        )?;
+                    }
+                    None => {
+                        views.detect(rec, prefix, args, |pipeline, rois| match rois {
+                            Some(rois) => pipeline.process_rois(img, rois),
+                            None => pipeline.process(img),
+                        })?;
+                        views.log_decoding(rec, prefix, args)?;
+                        views.log(rec, prefix, args, self.calibration.as_ref(), img)?;
+                    }
+                }
+            }
+            FrameRef::Gray(img) => {
+                log_image(rec, &entity_path(prefix, "Original Frame"), img)?;
+
+                // GRAY8 frames skip the color conversion and go straight into the pipeline
+                let grayscale_image = &mut views.pipeline.grayscale_image;
+                match &self.undistorter {
+                    Some(undistorter) => undistorter.undistort_image(img, grayscale_image)?,
+                    None => grayscale_image
+                        .as_slice_mut()
+                        .copy_from_slice(img.as_slice()),
+                }
+
+                if self.undistorter.is_some() {
+                    log_image(
+                        rec,
+                        &entity_path(prefix, "Undistorted Frame"),
+                        &views.pipeline.grayscale_image,
+                    )?;
+                }
+
+                views.detect(rec, prefix, args, |pipeline, rois| match rois {
+                    Some(rois) => pipeline.process_gray_rois(rois),
+                    None => pipeline.process_gray(),
+                })?;
+                views.log_decoding(rec, prefix, args)?;
+
+                // Swap the frame out of the pipeline while the views are logged, instead of
+                // copying it
+                std::mem::swap(&mut views.pipeline.grayscale_image, &mut self.gray_frame);
+                let result = views.log(
+                    rec,
+                    prefix,
+                    args,
+                    self.calibration.as_ref(),
+                    &self.gray_frame,
+                );
+                std::mem::swap(&mut views.pipeline.grayscale_image, &mut self.gray_frame);
+                result?;
+            }
+        }
+
+        views.end_frame();
+
+        Ok(())
+    }
+}
+
+impl Views {
+    fn begin_frame(
+        &mut self,
+        rec: &rerun::RecordingStream,
+        args: &Args,
+        captured_at: Instant,
+        capture_time: SystemTime,
+    ) {
+        set_frame_time(rec, self.frame_idx, capture_time);
+        self.capture_time = capture_time;
+
+        // Time between the captures rather than the processing, which is delayed by the queue
+        self.dt = self
+            .last_frame_time
+            .map_or(0.0, |last| (captured_at - last).as_secs_f32());
+        self.last_frame_time = Some(captured_at);
+
+        // Without any region to search, e.g. before the first tag is tracked, scan the whole frame
+        self.rois.clear();
+        if (args.roi || args.roi_mask.is_some())
+            && self.frame_idx % args.full_scan_interval.max(1) != 0
+        {
+            self.rois.extend_from_slice(&self.mask_rois);
+            if args.roi {
+                self.rois.extend(self.tracker.predicted_rois(self.dt));
+            }
+        }
+    }
+
+    /// Advances the frame index once every view of the frame is logged.
+    fn end_frame(&mut self) {
+        self.frame_idx += 1;
+    }
+
+    /// Runs the pipeline with `process`, which gets the regions to search or `None` for a full
+    /// scan, and measures its allocations.
+    fn detect<F>(
+        &mut self,
+        rec: &rerun::RecordingStream,
+        prefix: &str,
+        args: &Args,
+        process: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnOnce(&mut Pipeline, Option<&[Roi]>) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let full_scan = self.rois.is_empty();
+        let rois = (!full_scan).then_some(self.rois.as_slice());
+
+        let (result, allocations) = measure_allocations(|| process(&mut self.pipeline, rois));
+        result?;
+
+        if args.roi || args.roi_mask.is_some() {
+            log_active_rois(rec, prefix, &self.pipeline, full_scan)?;
+        }
+
+        if let Some(allocations) = allocations {
+            rec.log(
+                entity_path(prefix, "Allocations/blocks"),
+                &rerun::Scalars::new([allocations.blocks as f64]),
+            )?;
+            rec.log(
+                entity_path(prefix, "Allocations/bytes"),
+                &rerun::Scalars::new([allocations.bytes as f64]),
+            )?;
+
+            if self.frame_idx > 0 {
+                self.steady_state_allocations.blocks += allocations.blocks;
+                self.steady_state_allocations.bytes += allocations.bytes;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn log_decoding(
+        &self,
+        rec: &rerun::RecordingStream,
+        prefix: &str,
+        args: &Args,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !args.debug_decoding {
+            return Ok(());
+        }
+
+        // Remove the quads of the previous frame
+        rec.log(entity_path(prefix, "Decoding"), &rerun::Clear::recursive())?;
+
+        let pipeline = &self.pipeline;
+        for (i, quad) in pipeline.fitted_quads.iter().enumerate() {
+            for family in &pipeline.config.tag_families {
+                let Some(debug) = debug_decode_quad(
+                    &pipeline.grayscale_image,
+                    quad,
+                    family,
+                    pipeline.config.decode_sharpening,
+                ) else {
+                    continue;
+                };
+
+                log_quad_decode(
+                    rec,
+                    &entity_path(prefix, &format!("Decoding/quad_{i}/{}", family.name)),
+                    &debug,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Logs every stage of the processed frame, `img` is the frame the pipeline ran on.
+    fn log<A: ImageAllocator, const C: usize>(
+        &mut self,
+        rec: &rerun::RecordingStream,
+        prefix: &str,
+        args: &Args,
+        calibration: Option<&CameraCalibration>,
+        img: &Image<u8, C, A>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pipeline = &mut self.pipeline;
+        let detection_size = pipeline.detection_size();
+
+        log_image(rec, &entity_path(prefix, "Detected Tags"), img)?;
 
         // rec.log(
         //     "Grayscale Frame",
         //     &rerun::Image::from_elements(
-        //         grayscale_image.as_slice(),
-        //         grayscale_image.size().into(),
+        //         pipeline.grayscale_image.as_slice(),
+        //         pipeline.grayscale_image.size().into(),
         //         rerun::ColorModel::L,
         //     ),
         // )?;
 
-        // Convert to binary
-        kornia_apriltag::threshold::adaptive_threshold(
-            &grayscale_image,
-            &mut binary_image,
-            &mut tile_min_max,
-            20,
-        )?;
-
-        let binary_slice = unsafe {
-            std::slice::from_raw_parts(
-                binary_image.as_slice().as_ptr() as *const u8,
-                binary_image.as_slice().len(),
-            )
-        };
-        rec.log(
-            "Adaptive Threshold Frame",
-            &rerun::Image::from_elements(binary_slice, img.size().into(), rerun::ColorModel::L),
-        )?;
-
-        // Find Connected Components
-        kornia_apriltag::segmentation::find_connected_components(&binary_image, &mut uf)?;
-
-        debug_connected_components(&mut segmentation_image, &mut uf);
         rec.log(
-            "Connected Components",
+            entity_path(prefix, "Adaptive Threshold Frame"),
             &rerun::Image::from_elements(
-                segmentation_image.as_slice(),
-                segmentation_image.size().into(),
-                rerun::ColorModel::RGB,
+                pipeline.binary_slice(),
+                detection_size.into(),
+                rerun::ColorModel::L,
             ),
         )?;
 
-        // Find Gradient Clusters
-        kornia_apriltag::segmentation::find_gradient_clusters(
-            &binary_image,
-            &mut uf,
-            &mut clusters,
+        if pipeline.decimation > 1 {
+            log_decimated_frame(rec, prefix, pipeline)?;
+        }
+
+        // The regions are segmented on their own buffers, only a full scan segments the frame
+        if pipeline.full_scan {
+            self.component_palette.begin_frame();
+            debug_connected_components(
+                &mut self.components,
+                &mut pipeline.uf,
+                &mut self.component_palette,
+            );
+            self.components
+                .log(rec, &entity_path(prefix, "Connected Components"))?;
+
+            self.cluster_palette.begin_frame();
+            debug_gradient_clusters(
+                &mut self.clusters,
+                &pipeline.clusters,
+                &mut self.cluster_palette,
+            );
+            self.clusters
+                .log(rec, &entity_path(prefix, "Gradient Clusters"))?;
+        } else {
+            rec.log(
+                entity_path(prefix, "Connected Components"),
+                &rerun::Clear::flat(),
+            )?;
+            rec.log(
+                entity_path(prefix, "Gradient Clusters"),
+                &rerun::Clear::flat(),
+            )?;
+        }
+
+        self.quad_palette.begin_frame();
+        debug_quad_fitting(
+            img,
+            &mut self.quads_image,
+            &pipeline.fitted_quads,
+            &mut self.quad_palette,
         );
+        log_image(rec, &entity_path(prefix, "Quads"), &self.quads_image)?;
 
-        debug_gradient_clusters(&mut cluster_image, &clusters);
-        rec.log(
-            "Gradient Clusters",
-            &rerun::Image::from_elements(
-                cluster_image.as_slice(),
-                cluster_image.size().into(),
-                rerun::ColorModel::RGB,
-            ),
-        )?;
+        if pipeline.decimation > 1 || pipeline.refine {
+            log_refinement(rec, prefix, pipeline)?;
+        }
 
-        // Quad Fitting
-        // TODO: Avoid multiple allocations
-        let mut quads = kornia_apriltag::quad::fit_quads(&binary_image, &mut clusters, &config);
+        if args.debug_rejected && !pipeline.full_scan {
+            rec.log(
+                entity_path(prefix, "Rejected Clusters"),
+                &rerun::Clear::recursive(),
+            )?;
+        } else if args.debug_rejected {
+            let rejected = find_rejected_clusters(
+                &pipeline.binary_image,
+                &mut pipeline.clusters,
+                &pipeline.config,
+            );
 
-        debug_quad_fitting(&img, &mut quads_image, &quads);
-        rec.log(
-            "Quads",
-            &rerun::Image::from_elements(
-                quads_image.as_slice(),
-                quads_image.size().into(),
-                rerun::ColorModel::RGB,
-            ),
-        )?;
+            // The clusters are in the coordinates of the decimated frame
+            if pipeline.decimation > 1 {
+                log_image(
+                    rec,
+                    &entity_path(prefix, "Rejected Clusters"),
+                    &pipeline.decimated_image,
+                )?;
+            } else {
+                log_image(rec, &entity_path(prefix, "Rejected Clusters"), img)?;
+            }
+            log_rejected_clusters(
+                rec,
+                &entity_path(prefix, "Rejected Clusters/clusters"),
+                &rejected,
+            )?;
+        }
 
-        // Detect AprilTag
-        let detections = kornia_apriltag::decoder::decode_tags(
-            &grayscale_image,
-            &mut quads,
-            &mut config,
-            &mut gray_model_pair,
-        );
+        self.poses.clear();
+
+        if let Some(calibration) = calibration {
+            log_image(rec, &entity_path(prefix, "world/camera"), img)?;
+
+            self.poses.extend(pipeline.detections.iter().map(|tag| {
+                let corners = tag.quad.corners.map(|corner| {
+                    if args.undistort == Some(UndistortMode::Corners) {
+                        calibration
+                            .undistort_pixel([corner.x as f64, corner.y as f64])
+                            .map(|v| v as f32)
+                    } else {
+                        [corner.x, corner.y]
+                    }
+                });
+                estimate_tag_pose(&corners, calibration, args.tag_size)
+            }));
+        }
+
+        self.detection_logger
+            .log(rec, &pipeline.detections, &self.poses)?;
+
+        let frame_idx = self.frame_idx;
+
+        if !self.exporters.is_empty() {
+            let record = FrameRecord::new(
+                frame_idx,
+                self.capture_time,
+                None,
+                &pipeline.detections,
+                &self.poses,
+            );
+
+            for exporter in &mut self.exporters {
+                exporter.write(&record)?;
+            }
+        }
+
+        if let Some(video) = &mut self.video {
+            copy_to_rgb(img, &mut self.annotated_image);
+            self.renderer
+                .render(&mut self.annotated_image, &pipeline.detections, &self.poses);
+            let captured_at = self.last_frame_time.unwrap_or_else(Instant::now);
+            video.write(&self.annotated_image, captured_at)?;
+        }
+
+        if args.track || args.roi {
+            let events = self
+                .tracker
+                .update(frame_idx, self.dt, &pipeline.detections);
+            log_tracks(
+                rec,
+                &entity_path(prefix, "Detected Tags"),
+                &entity_path(prefix, "Tracking"),
+                &self.tracker,
+                &events,
+                frame_idx,
+                self.dt,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Entity path of a camera, the cameras are logged under their own prefix if there are several.
+fn entity_path(prefix: &str, path: &str) -> String {
+    if prefix.is_empty() {
+        path.to_string()
+    } else {
+        format!("{prefix}/{path}")
+    }
+}
+
+/// Output file of a camera, the cameras get the prefix appended to the file name if there are
+/// several.
+fn source_file_path(path: &Path, prefix: &str) -> PathBuf {
+    if prefix.is_empty() {
+        return path.to_path_buf();
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{stem}_{prefix}.{}", extension.to_string_lossy()),
+        None => format!("{stem}_{prefix}"),
+    };
+
+    path.with_file_name(file_name)
+}
 
-        // Collect all tag quads and labels to draw all at once
-        let mut all_coords = Vec::new();
-        let mut all_labels = Vec::new();
+/// Logs an RGB or grayscale image.
+fn log_image<A: ImageAllocator, const C: usize>(
+    rec: &rerun::RecordingStream,
+    path: &str,
+    img: &Image<u8, C, A>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let color_model = if C == 1 {
+        rerun::ColorModel::L
+    } else {
+        rerun::ColorModel::RGB
+    };
 
-        for tag in &detections {
-            let coords = [
-                [tag.quad.corners[0].x, tag.quad.corners[0].y],
-                [tag.quad.corners[1].x, tag.quad.corners[1].y],
-                [tag.quad.corners[2].x, tag.quad.corners[2].y],
-                [tag.quad.corners[3].x, tag.quad.corners[3].y],
-                [tag.quad.corners[0].x, tag.quad.corners[0].y],
-            ];
-            all_coords.push(coords);
-            all_labels.push(tag.id.to_string());
+    rec.log(
+        path,
+        &rerun::Image::from_elements(img.as_slice(), img.size().into(), color_model),
+    )?;
+
+    Ok(())
+}
+
+/// Logs the decimated frame with the quads found on it.
+fn log_decimated_frame(
+    rec: &rerun::RecordingStream,
+    prefix: &str,
+    pipeline: &Pipeline,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let scale = pipeline.decimation as f32;
+
+    rec.log(
+        entity_path(prefix, "Decimated Frame"),
+        &rerun::Image::from_elements(
+            pipeline.decimated_image.as_slice(),
+            pipeline.decimated_image.size().into(),
+            rerun::ColorModel::L,
+        ),
+    )?;
+
+    rec.log(
+        entity_path(prefix, "Decimated Frame/quads"),
+        &rerun::LineStrips2D::new(pipeline.unrefined_corners.iter().map(|corners| {
+            outline(corners.map(|corner| {
+                [
+                    (corner.x + 0.5) / scale - 0.5,
+                    (corner.y + 0.5) / scale - 0.5,
+                ]
+            }))
+        }))
+        .with_colors([rerun::Color::from_rgb(255, 255, 0)]),
+    )?;
+
+    Ok(())
+}
+
+/// Logs the corners before and after the edge refinement on top of the "Quads" image, along with
+/// how far every corner moved.
+fn log_refinement(
+    rec: &rerun::RecordingStream,
+    prefix: &str,
+    pipeline: &Pipeline,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let before = &pipeline.unrefined_corners;
+    let after = pipeline.fitted_quads.iter().map(|quad| quad.corners);
+
+    rec.log(
+        entity_path(prefix, "Quads/unrefined"),
+        &rerun::LineStrips2D::new(
+            before
+                .iter()
+                .map(|corners| outline(corners.map(|corner| [corner.x, corner.y]))),
+        )
+        .with_colors([rerun::Color::from_rgb(255, 255, 0)]),
+    )?;
+
+    rec.log(
+        entity_path(prefix, "Quads/refined"),
+        &rerun::LineStrips2D::new(
+            after
+                .clone()
+                .map(|corners| outline(corners.map(|corner| [corner.x, corner.y]))),
+        )
+        .with_colors([rerun::Color::from_rgb(0, 255, 255)]),
+    )?;
+
+    let mut origins = Vec::new();
+    let mut vectors = Vec::new();
+    let mut labels = Vec::new();
+    let mut corrections = Vec::new();
+
+    for (before, after) in before.iter().zip(after) {
+        for (i, correction) in corner_corrections(before, &after).into_iter().enumerate() {
+            origins.push([before[i].x, before[i].y]);
+            vectors.push([after[i].x - before[i].x, after[i].y - before[i].y]);
+            labels.push(format!("{correction:.2} px"));
+            corrections.push(correction);
         }
+    }
+
+    rec.log(
+        entity_path(prefix, "Quads/corrections"),
+        &rerun::Arrows2D::from_vectors(vectors)
+            .with_origins(origins)
+            .with_labels(labels)
+            .with_colors([rerun::Color::from_rgb(255, 0, 255)]),
+    )?;
+
+    if !corrections.is_empty() {
+        let mean = corrections.iter().sum::<f32>() / corrections.len() as f32;
+        let max = corrections.iter().copied().fold(0.0, f32::max);
 
         rec.log(
-            "Detected Tags",
-            &rerun::LineStrips2D::new(all_coords).with_labels(all_labels),
+            entity_path(prefix, "Refinement/mean_correction"),
+            &rerun::Scalars::new([mean as f64]),
+        )?;
+        rec.log(
+            entity_path(prefix, "Refinement/max_correction"),
+            &rerun::Scalars::new([max as f64]),
         )?;
-
-        fps_counter.update();
-        uf.reset();
-        clusters.clear();
-        gray_model_pair.reset();
     }
 
-    webcam.close()?;
-    println!("Finished recording. Closing app.");
+    Ok(())
+}
+
+/// Logs the regions which were searched for tags, or nothing if the whole frame was scanned.
+fn log_active_rois(
+    rec: &rerun::RecordingStream,
+    prefix: &str,
+    pipeline: &Pipeline,
+    full_scan: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let scale = pipeline.decimation as f32;
+    let rois = pipeline
+        .active_rois
+        .iter()
+        .map(|roi| Roi {
+            min: [roi.x as f32 * scale, roi.y as f32 * scale],
+            max: [
+                (roi.x + roi.size.width) as f32 * scale,
+                (roi.y + roi.size.height) as f32 * scale,
+            ],
+        })
+        .collect::<Vec<_>>();
+
+    rec.log(
+        entity_path(prefix, "Detected Tags/active_rois"),
+        &rerun::Boxes2D::from_mins_and_sizes(
+            rois.iter().map(|roi| roi.min),
+            rois.iter().map(|roi| [roi.width(), roi.height()]),
+        )
+        .with_colors([rerun::Color::from_rgb(255, 128, 0)]),
+    )?;
+
+    let detection_size = pipeline.detection_size();
+    let searched_fraction = if full_scan {
+        1.0
+    } else {
+        let searched = pipeline
+            .active_rois
+            .iter()
+            .map(|roi| roi.size.width * roi.size.height)
+            .sum::<usize>();
+        searched as f64 / (detection_size.width * detection_size.height) as f64
+    };
+
+    rec.log(
+        entity_path(prefix, "ROI/full_scan"),
+        &rerun::Scalars::new([full_scan as u8 as f64]),
+    )?;
+    rec.log(
+        entity_path(prefix, "ROI/searched_fraction"),
+        &rerun::Scalars::new([searched_fraction]),
+    )?;
 
     Ok(())
 }
+
+/// Closed line strip through the corners of a quad.
+fn outline(corners: [[f32; 2]; 4]) -> Vec<[f32; 2]> {
+    let mut points = corners.to_vec();
+    points.push(corners[0]);
+    points
+}
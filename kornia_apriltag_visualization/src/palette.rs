@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+/// Hue step between consecutive palette colors, the golden angle keeps every new hue far away
+/// from all the previous ones.
+const GOLDEN_ANGLE: f64 = 137.507_764;
+/// Saturation and value levels cycled through, so colors with close hues still differ.
+const LEVELS: [(f32, f32); 3] = [(0.9, 1.0), (0.6, 0.85), (1.0, 0.7)];
+/// Minimum overlap for a cluster to keep the color of a cluster of the previous frame.
+const MIN_IOU: f32 = 0.3;
+/// Size of the grid cells the clusters of the previous frame are looked up in.
+const CELL_SIZE: usize = 32;
+
+/// Axis aligned bounding box of a cluster in pixels, `max` is inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoundingBox {
+    pub min: [usize; 2],
+    pub max: [usize; 2],
+}
+
+impl BoundingBox {
+    pub fn from_point(x: usize, y: usize) -> Self {
+        Self {
+            min: [x, y],
+            max: [x, y],
+        }
+    }
+
+    /// Bounding box of the points, `None` if there are none.
+    pub fn from_points(points: impl IntoIterator<Item = [usize; 2]>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let [x, y] = points.next()?;
+        let mut bbox = Self::from_point(x, y);
+
+        for [x, y] in points {
+            bbox.add(x, y);
+        }
+
+        Some(bbox)
+    }
+
+    pub fn add(&mut self, x: usize, y: usize) {
+        self.min = [self.min[0].min(x), self.min[1].min(y)];
+        self.max = [self.max[0].max(x), self.max[1].max(y)];
+    }
+
+    pub fn width(&self) -> usize {
+        self.max[0] - self.min[0] + 1
+    }
+
+    pub fn height(&self) -> usize {
+        self.max[1] - self.min[1] + 1
+    }
+
+    pub fn area(&self) -> usize {
+        self.width() * self.height()
+    }
+
+    pub fn center(&self) -> [usize; 2] {
+        [
+            (self.min[0] + self.max[0]) / 2,
+            (self.min[1] + self.max[1]) / 2,
+        ]
+    }
+
+    /// Intersection over union of the boxes.
+    pub fn iou(&self, other: &BoundingBox) -> f32 {
+        let min = [self.min[0].max(other.min[0]), self.min[1].max(other.min[1])];
+        let max = [self.max[0].min(other.max[0]), self.max[1].min(other.max[1])];
+
+        if min[0] > max[0] || min[1] > max[1] {
+            return 0.0;
+        }
+
+        let intersection = (max[0] - min[0] + 1) * (max[1] - min[1] + 1);
+        intersection as f32 / (self.area() + other.area() - intersection) as f32
+    }
+
+    /// Key which only depends on the box, so the same box always gets the same color.
+    pub fn key(&self) -> u64 {
+        [self.min[0], self.min[1], self.max[0], self.max[1]]
+            .iter()
+            .fold(0u64, |key, &v| splitmix64(key ^ v as u64))
+    }
+}
+
+/// Color `index` of a palette whose consecutive colors are far apart.
+pub fn palette_color(index: u64) -> [u8; 3] {
+    let hue = (index as f64 * GOLDEN_ANGLE) % 360.0;
+    let (saturation, value) = LEVELS[(index % LEVELS.len() as u64) as usize];
+
+    hsv_to_rgb(hue as f32, saturation, value)
+}
+
+/// Stable color of an arbitrary key, e.g. [`BoundingBox::key`]. Unlike `DefaultHasher`, the
+/// mixing never changes between runs or Rust versions.
+pub fn key_color(key: u64) -> [u8; 3] {
+    let hash = splitmix64(key);
+    let hue = (hash >> 40) as f32 / (1u64 << 24) as f32 * 360.0;
+    let (saturation, value) = LEVELS[(hash % LEVELS.len() as u64) as usize];
+
+    hsv_to_rgb(hue, saturation, value)
+}
+
+/// Colors of the clusters of a debug image. With persistence, a cluster overlapping a cluster of
+/// the previous frame keeps its color, so the colors don't flicker from frame to frame in rerun.
+/// Without it, the color only depends on the bounding box of the cluster.
+#[derive(Debug, Default)]
+pub struct Palette {
+    persistent: bool,
+    /// Clusters of the previous frame with their colors
+    previous: Vec<(BoundingBox, [u8; 3])>,
+    /// Clusters colored so far in this frame
+    current: Vec<(BoundingBox, [u8; 3])>,
+    /// Indices into `previous` by the grid cell of the center of the box
+    grid: HashMap<[usize; 2], Vec<usize>>,
+    /// Palette index of the next new cluster
+    next_index: u64,
+}
+
+impl Palette {
+    pub fn new(persistent: bool) -> Self {
+        Self {
+            persistent,
+            ..Default::default()
+        }
+    }
+
+    /// Starts a new frame, the clusters colored in the last frame become the ones colors are
+    /// inherited from.
+    pub fn begin_frame(&mut self) {
+        if !self.persistent {
+            return;
+        }
+
+        std::mem::swap(&mut self.previous, &mut self.current);
+        self.current.clear();
+
+        for cells in self.grid.values_mut() {
+            cells.clear();
+        }
+        for (i, (bbox, _)) in self.previous.iter().enumerate() {
+            let [x, y] = bbox.center();
+            self.grid
+                .entry([x / CELL_SIZE, y / CELL_SIZE])
+                .or_default()
+                .push(i);
+        }
+    }
+
+    /// Color of the cluster with the bounding box.
+    pub fn color(&mut self, bbox: BoundingBox) -> [u8; 3] {
+        if !self.persistent {
+            return key_color(bbox.key());
+        }
+
+        let color = self
+            .best_match(&bbox)
+            .map(|i| self.previous[i].1)
+            .unwrap_or_else(|| {
+                self.next_index += 1;
+                palette_color(self.next_index)
+            });
+        self.current.push((bbox, color));
+
+        color
+    }
+
+    /// Cluster of the previous frame overlapping the box the most.
+    fn best_match(&self, bbox: &BoundingBox) -> Option<usize> {
+        let [x, y] = bbox.center();
+        let [cx, cy] = [x / CELL_SIZE, y / CELL_SIZE];
+
+        // Larger clusters may have moved their center further, search the cells they cover
+        let reach = [
+            bbox.width() / CELL_SIZE / 2 + 1,
+            bbox.height() / CELL_SIZE / 2 + 1,
+        ];
+
+        let mut best = None;
+        for gy in cy.saturating_sub(reach[1])..=cy + reach[1] {
+            for gx in cx.saturating_sub(reach[0])..=cx + reach[0] {
+                let Some(candidates) = self.grid.get(&[gx, gy]) else {
+                    continue;
+                };
+
+                for &i in candidates {
+                    let iou = bbox.iou(&self.previous[i].0);
+                    if iou >= MIN_IOU && best.is_none_or(|(_, best_iou)| iou > best_iou) {
+                        best = Some((i, iou));
+                    }
+                }
+            }
+        }
+
+        best.map(|(i, _)| i)
+    }
+}
+
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [u8; 3] {
+    let c = value * saturation;
+    let h = (hue % 360.0) / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    let m = value - c;
+    [r, g, b].map(|channel| ((channel + m) * 255.0).round() as u8)
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+
+use kornia_apriltag::{
+    DecodeTagsConfig,
+    decoder::{Detection, GrayModelPair, decode_tags},
+    quad::{Quad, fit_quads},
+    segmentation::{GradientInfo, find_connected_components, find_gradient_clusters},
+    threshold::{TileMinMax, adaptive_threshold},
+    union_find::UnionFind,
+    utils::{Pixel, Point2d},
+};
+use kornia_image::{
+    Image, ImageSize,
+    allocator::{CpuAllocator, ImageAllocator},
+};
+use kornia_imgproc::color::gray_from_rgb_u8;
+
+use crate::{
+    homography::homography_from_corners,
+    refine::refine_edges,
+    roi::{PixelRoi, Roi, merge_pixel_rois},
+};
+
+const TILE_SIZE: usize = 4;
+const MIN_WHITE_BLACK_DIFF: u8 = 20;
+/// The regions are aligned to multiples of the tile size, coarse enough that the regions of the
+/// tracked tags keep their size over many frames and reuse their buffers.
+const ROI_ALIGN: usize = 8 * TILE_SIZE;
+/// Maximum number of region sizes buffers are kept for.
+const MAX_ROI_BUFFERS: usize = 16;
+
+/// Runs every step of the AprilTag decoding on preallocated buffers, keeping the intermediate
+/// results of the last frame around so they can be visualized.
+///
+/// With a decimation factor above 1 the quads are searched on a downscaled copy of the frame, then
+/// scaled back and refined on the full resolution frame, which is also used for decoding.
+pub struct Pipeline {
+    pub config: DecodeTagsConfig,
+    pub decimation: usize,
+    /// Refine the edges of the quads even if the frame isn't decimated
+    pub refine: bool,
+    pub grayscale_image: Image<u8, 1, CpuAllocator>,
+    /// Downscaled frame the quads are searched on, only used if `decimation` is above 1
+    pub decimated_image: Image<u8, 1, CpuAllocator>,
+    pub binary_image: Image<Pixel, 1, CpuAllocator>,
+    pub tile_min_max: TileMinMax,
+    pub uf: UnionFind,
+    pub clusters: HashMap<(usize, usize), Vec<GradientInfo>>,
+    pub gray_model_pair: GrayModelPair,
+    /// Quads handed to the decoder, which reorders and rewrites them
+    pub quads: Vec<Quad>,
+    /// Quads as they were fitted and refined, before decoding
+    pub fitted_quads: Vec<Quad>,
+    /// Whether the whole frame was searched, otherwise only `active_rois` were and `uf` and
+    /// `clusters` stay empty
+    pub full_scan: bool,
+    /// Regions of the detection image which were searched, empty after a full frame scan
+    pub active_rois: Vec<PixelRoi>,
+    /// Corners of the quads at full resolution before they were refined, empty if the quads
+    /// weren't refined
+    pub unrefined_corners: Vec<[Point2d<f32>; 4]>,
+    pub detections: Vec<Detection>,
+    /// Buffers of the region detection by the size of the region
+    roi_buffers: Vec<RoiBuffers>,
+    /// Number of processed frames, used to drop the least recently used region buffers
+    frames: u64,
+}
+
+/// Buffers of the quad detection in a region, reused by the regions of the same size.
+struct RoiBuffers {
+    size: ImageSize,
+    crop: Image<u8, 1, CpuAllocator>,
+    binary: Image<Pixel, 1, CpuAllocator>,
+    tile_min_max: TileMinMax,
+    uf: UnionFind,
+    clusters: HashMap<(usize, usize), Vec<GradientInfo>>,
+    /// Frame the buffers were last used in
+    last_used: u64,
+}
+
+impl Pipeline {
+    pub fn new(
+        size: ImageSize,
+        config: DecodeTagsConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_decimation(size, config, 1)
+    }
+
+    /// Creates a pipeline which searches the quads on the frame downscaled by `decimation`.
+    pub fn with_decimation(
+        size: ImageSize,
+        config: DecodeTagsConfig,
+        decimation: usize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if decimation == 0 {
+            return Err("Decimation factor must be at least 1".into());
+        }
+
+        let detection_size = ImageSize {
+            width: size.width / decimation,
+            height: size.height / decimation,
+        };
+
+        Ok(Self {
+            config,
+            decimation,
+            refine: false,
+            grayscale_image: Image::from_size_val(size, 0u8, CpuAllocator)?,
+            decimated_image: Image::from_size_val(detection_size, 0u8, CpuAllocator)?,
+            binary_image: Image::from_size_val(detection_size, Pixel::Skip, CpuAllocator)?,
+            tile_min_max: TileMinMax::new(detection_size, TILE_SIZE),
+            uf: UnionFind::new(detection_size.width * detection_size.height),
+            clusters: HashMap::new(),
+            gray_model_pair: GrayModelPair::new(),
+            quads: Vec::new(),
+            fitted_quads: Vec::new(),
+            full_scan: true,
+            active_rois: Vec::new(),
+            unrefined_corners: Vec::new(),
+            detections: Vec::new(),
+            roi_buffers: Vec::new(),
+            frames: 0,
+        })
+    }
+
+    /// Size of the image the quads are searched on.
+    pub fn detection_size(&self) -> ImageSize {
+        self.binary_image.size()
+    }
+
+    /// Runs every step on the frame, clearing the results of the previous one.
+    pub fn process<A: ImageAllocator>(
+        &mut self,
+        img: &Image<u8, 3, A>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Convert to grayscale
+        gray_from_rgb_u8(img, &mut self.grayscale_image)?;
+
+        self.process_gray()
+    }
+
+    /// Same as [`Pipeline::process`], but only searches the quads inside of the regions. The
+    /// regions are in the coordinates of the full resolution frame.
+    pub fn process_rois<A: ImageAllocator>(
+        &mut self,
+        img: &Image<u8, 3, A>,
+        rois: &[Roi],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        gray_from_rgb_u8(img, &mut self.grayscale_image)?;
+
+        self.process_gray_rois(rois)
+    }
+
+    /// Same as [`Pipeline::process`], but for a frame already stored in `grayscale_image`.
+    pub fn process_gray(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.detect(None)
+    }
+
+    /// Same as [`Pipeline::process_rois`], but for a frame already stored in `grayscale_image`.
+    pub fn process_gray_rois(&mut self, rois: &[Roi]) -> Result<(), Box<dyn std::error::Error>> {
+        self.detect(Some(rois))
+    }
+
+    fn detect(&mut self, rois: Option<&[Roi]>) -> Result<(), Box<dyn std::error::Error>> {
+        self.reset();
+        self.full_scan = rois.is_none();
+        self.frames += 1;
+
+        let detection_image = if self.decimation > 1 {
+            decimate(
+                &self.grayscale_image,
+                &mut self.decimated_image,
+                self.decimation,
+            );
+            &self.decimated_image
+        } else {
+            &self.grayscale_image
+        };
+
+        if let Some(rois) = rois {
+            let scale = self.decimation as f32;
+            let size = detection_image.size();
+            self.active_rois.extend(
+                rois.iter()
+                    .filter_map(|roi| roi.to_pixels(size, scale, ROI_ALIGN)),
+            );
+            // Merged after the alignment, which can make the regions overlap
+            merge_pixel_rois(&mut self.active_rois, size, ROI_ALIGN);
+
+            // Only the regions are thresholded, the rest of the binary image is skipped
+            self.binary_image.as_slice_mut().fill(Pixel::Skip);
+            self.quads.clear();
+
+            for roi in &self.active_rois {
+                let buffers = roi_buffers(&mut self.roi_buffers, roi.size, self.frames)?;
+                let quads =
+                    buffers.detect(detection_image, roi, &self.config, &mut self.binary_image)?;
+                self.quads.extend(quads);
+            }
+        } else {
+            // Convert to binary
+            adaptive_threshold(
+                detection_image,
+                &mut self.binary_image,
+                &mut self.tile_min_max,
+                MIN_WHITE_BLACK_DIFF,
+            )?;
+
+            // Find Connected Components
+            find_connected_components(&self.binary_image, &mut self.uf)?;
+
+            // Find Gradient Clusters
+            find_gradient_clusters(&self.binary_image, &mut self.uf, &mut self.clusters);
+
+            // Quad Fitting
+            // TODO: Avoid multiple allocations, `fit_quads` returns a new vector every frame
+            self.quads = fit_quads(&self.binary_image, &mut self.clusters, &self.config);
+        }
+
+        if self.decimation > 1 || self.refine {
+            self.refine_quads();
+        }
+
+        // Keep the fitted quads for the debug views, the buffer is reused between frames
+        self.fitted_quads.clone_from(&self.quads);
+
+        // Detect AprilTag
+        // TODO: Avoid multiple allocations, `decode_tags` returns a new vector every frame
+        self.detections = decode_tags(
+            &self.grayscale_image,
+            &mut self.quads,
+            &mut self.config,
+            &mut self.gray_model_pair,
+        );
+
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        self.uf.reset();
+        self.clusters.clear();
+        self.gray_model_pair.reset();
+        self.unrefined_corners.clear();
+        self.active_rois.clear();
+    }
+
+    /// Refines the edges of the quads on the full resolution frame. Quads found on the decimated
+    /// image are scaled to the full resolution first, since their corners are off by up to the
+    /// decimation factor. Without decimation the scale is 1 and only the edges are refined.
+    fn refine_quads(&mut self) {
+        let scale = self.decimation as f32;
+
+        for quad in &mut self.quads {
+            // Pixel `i` of the decimated image is centered on the pixels `i * scale..(i + 1) * scale`
+            for corner in &mut quad.corners {
+                corner.x = (corner.x + 0.5) * scale - 0.5;
+                corner.y = (corner.y + 0.5) * scale - 0.5;
+            }
+
+            self.unrefined_corners.push(quad.corners);
+
+            refine_edges(&self.grayscale_image, quad, scale + 1.0);
+
+            if let Some(homography) = homography_from_corners(&quad.corners) {
+                quad.homography = homography;
+            }
+        }
+    }
+
+    /// Binary image reinterpreted as bytes, so it can be logged as a grayscale image.
+    pub fn binary_slice(&self) -> &[u8] {
+        // SAFETY: `Pixel` is a `u8` enum
+        unsafe {
+            std::slice::from_raw_parts(
+                self.binary_image.as_slice().as_ptr() as *const u8,
+                self.binary_image.as_slice().len(),
+            )
+        }
+    }
+}
+
+/// Buffers for a region of `size`, the least recently used buffers are dropped if there are
+/// buffers for too many sizes.
+fn roi_buffers(
+    pool: &mut Vec<RoiBuffers>,
+    size: ImageSize,
+    frame: u64,
+) -> Result<&mut RoiBuffers, Box<dyn std::error::Error>> {
+    let idx = match pool.iter().position(|buffers| buffers.size == size) {
+        Some(idx) => idx,
+        None => {
+            if pool.len() >= MAX_ROI_BUFFERS {
+                let lru = (0..pool.len())
+                    .min_by_key(|&i| pool[i].last_used)
+                    .unwrap_or(0);
+                pool.swap_remove(lru);
+            }
+
+            pool.push(RoiBuffers {
+                size,
+                crop: Image::from_size_val(size, 0u8, CpuAllocator)?,
+                binary: Image::from_size_val(size, Pixel::Skip, CpuAllocator)?,
+                tile_min_max: TileMinMax::new(size, TILE_SIZE),
+                uf: UnionFind::new(size.width * size.height),
+                clusters: HashMap::new(),
+                last_used: frame,
+            });
+            pool.len() - 1
+        }
+    };
+
+    let buffers = &mut pool[idx];
+    buffers.last_used = frame;
+    Ok(buffers)
+}
+
+impl RoiBuffers {
+    /// Runs the quad detection on a crop of `src`, writing the binary crop into `binary_image`
+    /// and returning the quads in the coordinates of `src`.
+    fn detect(
+        &mut self,
+        src: &Image<u8, 1, CpuAllocator>,
+        roi: &PixelRoi,
+        config: &DecodeTagsConfig,
+        binary_image: &mut Image<Pixel, 1, CpuAllocator>,
+    ) -> Result<Vec<Quad>, Box<dyn std::error::Error>> {
+        let width = roi.size.width;
+
+        for (y, row) in self.crop.as_slice_mut().chunks_exact_mut(width).enumerate() {
+            let start = (roi.y + y) * src.width() + roi.x;
+            row.copy_from_slice(&src.as_slice()[start..start + width]);
+        }
+
+        self.uf.reset();
+        self.clusters.clear();
+
+        adaptive_threshold(
+            &self.crop,
+            &mut self.binary,
+            &mut self.tile_min_max,
+            MIN_WHITE_BLACK_DIFF,
+        )?;
+        find_connected_components(&self.binary, &mut self.uf)?;
+        find_gradient_clusters(&self.binary, &mut self.uf, &mut self.clusters);
+        // TODO: Avoid multiple allocations, `fit_quads` returns a new vector for every region
+        let mut quads = fit_quads(&self.binary, &mut self.clusters, config);
+
+        for quad in &mut quads {
+            for corner in &mut quad.corners {
+                corner.x += roi.x as f32;
+                corner.y += roi.y as f32;
+            }
+
+            if let Some(homography) = homography_from_corners(&quad.corners) {
+                quad.homography = homography;
+            }
+        }
+
+        let dst_width = binary_image.width();
+        for (y, row) in self.binary.as_slice().chunks_exact(width).enumerate() {
+            let start = (roi.y + y) * dst_width + roi.x;
+            binary_image.as_slice_mut()[start..start + width].copy_from_slice(row);
+        }
+
+        Ok(quads)
+    }
+}
+
+/// Downscales `src` by averaging blocks of `factor` x `factor` pixels.
+fn decimate(src: &Image<u8, 1, CpuAllocator>, dst: &mut Image<u8, 1, CpuAllocator>, factor: usize) {
+    let src_width = src.width();
+    let dst_width = dst.width();
+    let src_data = src.as_slice();
+    let area = (factor * factor) as u32;
+
+    for (y, row) in dst.as_slice_mut().chunks_exact_mut(dst_width).enumerate() {
+        for (x, px) in row.iter_mut().enumerate() {
+            let mut sum = 0u32;
+
+            for src_row in src_data[y * factor * src_width..]
+                .chunks_exact(src_width)
+                .take(factor)
+            {
+                sum += src_row[x * factor..(x + 1) * factor]
+                    .iter()
+                    .map(|&v| v as u32)
+                    .sum::<u32>();
+            }
+
+            *px = (sum / area) as u8;
+        }
+    }
+}
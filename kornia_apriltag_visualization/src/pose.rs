@@ -0,0 +1,501 @@
+use serde::Serialize;
+
+use crate::{
+    calibration::CameraCalibration,
+    homography::{TAG_CORNERS, homography_from_points},
+};
+
+const MAX_ITERATIONS: usize = 20;
+const BOX_THICKNESS: f32 = 0.002;
+
+/// Pose of a tag in the camera frame, with x right, y down and z forward.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct TagPose {
+    /// Rotation from the tag frame to the camera frame, row major
+    pub rotation: [[f64; 3]; 3],
+    /// Position of the tag center in the camera frame in meters
+    pub translation: [f64; 3],
+    /// Root mean square reprojection error of the corners in pixels
+    pub reprojection_error: f64,
+    /// Width of the black border of the tag in meters
+    pub tag_size: f64,
+}
+
+impl TagPose {
+    /// Projects a point of the tag frame in meters into the image, with the lens distortion if
+    /// `distort` is set. Returns `None` for points behind the camera.
+    pub fn project(
+        &self,
+        calibration: &CameraCalibration,
+        point: [f64; 3],
+        distort: bool,
+    ) -> Option<[f64; 2]> {
+        let [x, y, z] = transform(&self.rotation, &self.translation, &point);
+        if z <= 0.0 {
+            return None;
+        }
+
+        let normalized = [x / z, y / z];
+        let [u, v] = if distort {
+            calibration.distort(normalized)
+        } else {
+            normalized
+        };
+
+        Some([
+            calibration.fx * u + calibration.cx,
+            calibration.fy * v + calibration.cy,
+        ])
+    }
+}
+
+/// Estimates the pose of a tag from its undistorted corners. The initial pose is decomposed from
+/// the homography and then refined by minimizing the reprojection error of the corners with
+/// Levenberg-Marquardt.
+pub fn estimate_tag_pose(
+    corners: &[[f32; 2]; 4],
+    calibration: &CameraCalibration,
+    tag_size: f64,
+) -> Option<TagPose> {
+    let image_points = corners.map(|[x, y]| [x as f64, y as f64]);
+    let half = tag_size / 2.0;
+    let object_points = TAG_CORNERS.map(|[x, y]| [x * half, y * half, 0.0]);
+
+    let (rotation, translation) = pose_from_homography(&image_points, calibration, half)?;
+    let params = refine_pose(
+        rotation_to_vector(&rotation),
+        translation,
+        &object_points,
+        &image_points,
+        calibration,
+    );
+
+    let residuals = reprojection_residuals(&params, &object_points, &image_points, calibration);
+    let reprojection_error = (residuals.iter().map(|r| r * r).sum::<f64>() / 4.0).sqrt();
+
+    Some(TagPose {
+        rotation: vector_to_rotation([params[0], params[1], params[2]]),
+        translation: [params[3], params[4], params[5]],
+        reprojection_error,
+        tag_size,
+    })
+}
+
+/// Decomposes the homography of the tag into a rotation and translation.
+fn pose_from_homography(
+    image_points: &[[f64; 2]; 4],
+    calibration: &CameraCalibration,
+    half_size: f64,
+) -> Option<([[f64; 3]; 3], [f64; 3])> {
+    let h = homography_from_points(&TAG_CORNERS, image_points)?.map(|v| v as f64);
+
+    // Columns of inverse(K) * H
+    let columns = [0, 1, 2].map(|j| {
+        [
+            (h[j] - calibration.cx * h[6 + j]) / calibration.fx,
+            (h[3 + j] - calibration.cy * h[6 + j]) / calibration.fy,
+            h[6 + j],
+        ]
+    });
+
+    let mut scale = 1.0 / (norm(columns[0]) * norm(columns[1])).sqrt();
+    // The tag must be in front of the camera
+    if columns[2][2] * scale < 0.0 {
+        scale = -scale;
+    }
+
+    let r1 = normalize(columns[0].map(|v| v * scale));
+    let r2 = columns[1].map(|v| v * scale);
+    let r2 = normalize(sub(r2, r1.map(|v| v * dot(r1, r2))));
+    let r3 = cross(r1, r2);
+
+    let rotation = [
+        [r1[0], r2[0], r3[0]],
+        [r1[1], r2[1], r3[1]],
+        [r1[2], r2[2], r3[2]],
+    ];
+    // The tag frame of the homography is in units of half the tag size
+    let translation = columns[2].map(|v| v * scale * half_size);
+
+    Some((rotation, translation))
+}
+
+/// Iterative PnP, minimizes the reprojection error over the rotation vector and translation.
+fn refine_pose(
+    rotation: [f64; 3],
+    translation: [f64; 3],
+    object_points: &[[f64; 3]; 4],
+    image_points: &[[f64; 2]; 4],
+    calibration: &CameraCalibration,
+) -> [f64; 6] {
+    let mut params = [
+        rotation[0],
+        rotation[1],
+        rotation[2],
+        translation[0],
+        translation[1],
+        translation[2],
+    ];
+    let cost = |params: &[f64; 6]| {
+        reprojection_residuals(params, object_points, image_points, calibration)
+            .iter()
+            .map(|r| r * r)
+            .sum::<f64>()
+    };
+
+    let mut current_cost = cost(&params);
+    let mut lambda = 1e-3;
+
+    for _ in 0..MAX_ITERATIONS {
+        let residuals = reprojection_residuals(&params, object_points, image_points, calibration);
+
+        // Numerical jacobian with central differences
+        let mut jacobian = [[0.0; 6]; 8];
+        for k in 0..6 {
+            let eps = 1e-6 * params[k].abs().max(1.0);
+            let mut plus = params;
+            let mut minus = params;
+            plus[k] += eps;
+            minus[k] -= eps;
+
+            let r_plus = reprojection_residuals(&plus, object_points, image_points, calibration);
+            let r_minus = reprojection_residuals(&minus, object_points, image_points, calibration);
+
+            for i in 0..8 {
+                jacobian[i][k] = (r_plus[i] - r_minus[i]) / (2.0 * eps);
+            }
+        }
+
+        // Normal equations (JᵀJ + λ diag(JᵀJ)) δ = -Jᵀr
+        let mut a = [[0.0; 7]; 6];
+        for row in 0..6 {
+            for col in 0..6 {
+                a[row][col] = (0..8).map(|i| jacobian[i][row] * jacobian[i][col]).sum();
+            }
+            a[row][row] *= 1.0 + lambda;
+            a[row][6] = -(0..8).map(|i| jacobian[i][row] * residuals[i]).sum::<f64>();
+        }
+
+        let Some(delta) = solve(a) else {
+            break;
+        };
+
+        let mut candidate = params;
+        for (param, delta) in candidate.iter_mut().zip(delta) {
+            *param += delta;
+        }
+
+        let candidate_cost = cost(&candidate);
+        if candidate_cost < current_cost {
+            let improvement = current_cost - candidate_cost;
+            params = candidate;
+            current_cost = candidate_cost;
+            lambda /= 10.0;
+
+            if improvement < 1e-12 {
+                break;
+            }
+        } else {
+            lambda *= 10.0;
+        }
+    }
+
+    params
+}
+
+fn reprojection_residuals(
+    params: &[f64; 6],
+    object_points: &[[f64; 3]; 4],
+    image_points: &[[f64; 2]; 4],
+    calibration: &CameraCalibration,
+) -> [f64; 8] {
+    let rotation = vector_to_rotation([params[0], params[1], params[2]]);
+    let mut residuals = [0.0; 8];
+
+    for (i, (object, image)) in object_points.iter().zip(image_points).enumerate() {
+        let point = transform(&rotation, &[params[3], params[4], params[5]], object);
+        let [u, v] = calibration.project(point);
+
+        residuals[i * 2] = u - image[0];
+        residuals[i * 2 + 1] = v - image[1];
+    }
+
+    residuals
+}
+
+/// Solves the 6x6 linear system given as an augmented matrix with gaussian elimination.
+fn solve(mut a: [[f64; 7]; 6]) -> Option<[f64; 6]> {
+    for col in 0..6 {
+        let pivot = (col..6).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+
+        if a[pivot][col].abs() < 1e-15 {
+            return None;
+        }
+
+        a.swap(col, pivot);
+
+        for row in 0..6 {
+            if row == col {
+                continue;
+            }
+
+            let pivot_row = a[col];
+            let factor = a[row][col] / pivot_row[col];
+            for (value, pivot) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot;
+            }
+        }
+    }
+
+    Some([0, 1, 2, 3, 4, 5].map(|i| a[i][6] / a[i][i]))
+}
+
+/// Rodrigues formula, converts a rotation vector into a rotation matrix.
+fn vector_to_rotation(vector: [f64; 3]) -> [[f64; 3]; 3] {
+    let theta = norm(vector);
+
+    if theta < 1e-12 {
+        return [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    }
+
+    let [x, y, z] = vector.map(|v| v / theta);
+    let (sin, cos) = theta.sin_cos();
+    let c = 1.0 - cos;
+
+    [
+        [cos + x * x * c, x * y * c - z * sin, x * z * c + y * sin],
+        [y * x * c + z * sin, cos + y * y * c, y * z * c - x * sin],
+        [z * x * c - y * sin, z * y * c + x * sin, cos + z * z * c],
+    ]
+}
+
+/// Inverse of [`vector_to_rotation`].
+fn rotation_to_vector(r: &[[f64; 3]; 3]) -> [f64; 3] {
+    let cos = ((r[0][0] + r[1][1] + r[2][2] - 1.0) / 2.0).clamp(-1.0, 1.0);
+    let theta = cos.acos();
+
+    if theta < 1e-12 {
+        return [0.0; 3];
+    }
+
+    let axis = [r[2][1] - r[1][2], r[0][2] - r[2][0], r[1][0] - r[0][1]];
+
+    if (std::f64::consts::PI - theta).abs() < 1e-6 {
+        // The axis is the eigenvector of the largest diagonal element
+        let i = (0..3)
+            .max_by(|&a, &b| r[a][a].total_cmp(&r[b][b]))
+            .unwrap_or_default();
+        let mut axis = [0.0; 3];
+        axis[i] = ((r[i][i] + 1.0) / 2.0).sqrt();
+        for j in (0..3).filter(|&j| j != i) {
+            axis[j] = r[i][j] / (2.0 * axis[i]);
+        }
+
+        return axis.map(|v| v * theta);
+    }
+
+    axis.map(|v| v * theta / (2.0 * theta.sin()))
+}
+
+/// Converts the rotation matrix into a `[x, y, z, w]` quaternion.
+pub fn rotation_to_quaternion(r: &[[f64; 3]; 3]) -> [f32; 4] {
+    let trace = r[0][0] + r[1][1] + r[2][2];
+
+    let [x, y, z, w] = if trace > 0.0 {
+        let s = (trace + 1.0).sqrt() * 2.0;
+        [
+            (r[2][1] - r[1][2]) / s,
+            (r[0][2] - r[2][0]) / s,
+            (r[1][0] - r[0][1]) / s,
+            0.25 * s,
+        ]
+    } else if r[0][0] > r[1][1] && r[0][0] > r[2][2] {
+        let s = (1.0 + r[0][0] - r[1][1] - r[2][2]).sqrt() * 2.0;
+        [
+            0.25 * s,
+            (r[0][1] + r[1][0]) / s,
+            (r[0][2] + r[2][0]) / s,
+            (r[2][1] - r[1][2]) / s,
+        ]
+    } else if r[1][1] > r[2][2] {
+        let s = (1.0 + r[1][1] - r[0][0] - r[2][2]).sqrt() * 2.0;
+        [
+            (r[0][1] + r[1][0]) / s,
+            0.25 * s,
+            (r[1][2] + r[2][1]) / s,
+            (r[0][2] - r[2][0]) / s,
+        ]
+    } else {
+        let s = (1.0 + r[2][2] - r[0][0] - r[1][1]).sqrt() * 2.0;
+        [
+            (r[0][2] + r[2][0]) / s,
+            (r[1][2] + r[2][1]) / s,
+            0.25 * s,
+            (r[1][0] - r[0][1]) / s,
+        ]
+    };
+
+    [x as f32, y as f32, z as f32, w as f32]
+}
+
+/// Logs the tag as a transform with a thin box and its axes, so it shows up in the 3D view.
+pub fn log_tag_pose(
+    rec: &rerun::RecordingStream,
+    path: &str,
+    pose: &TagPose,
+    label: &str,
+    [r, g, b]: [u8; 3],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let half = pose.tag_size as f32 / 2.0;
+
+    rec.log(
+        path,
+        &rerun::Transform3D::from_translation_rotation(
+            pose.translation.map(|v| v as f32),
+            rerun::Quaternion::from_xyzw(rotation_to_quaternion(&pose.rotation)),
+        ),
+    )?;
+
+    rec.log(
+        format!("{path}/box"),
+        &rerun::Boxes3D::from_half_sizes([[half, half, BOX_THICKNESS]])
+            .with_colors([rerun::Color::from_rgb(r, g, b)])
+            .with_labels([label]),
+    )?;
+
+    rec.log(
+        format!("{path}/axes"),
+        &rerun::Arrows3D::from_vectors([[half, 0.0, 0.0], [0.0, half, 0.0], [0.0, 0.0, half]])
+            .with_colors([
+                rerun::Color::from_rgb(255, 0, 0),
+                rerun::Color::from_rgb(0, 255, 0),
+                rerun::Color::from_rgb(0, 0, 255),
+            ]),
+    )?;
+
+    Ok(())
+}
+
+fn transform(rotation: &[[f64; 3]; 3], translation: &[f64; 3], point: &[f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| dot(rotation[i], *point) + translation[i])
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn norm(a: [f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let n = norm(a);
+    a.map(|v| v / n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::calibration::DistortionModel;
+
+    const TAG_SIZE: f64 = 0.16;
+
+    fn calibration() -> CameraCalibration {
+        CameraCalibration {
+            width: 640,
+            height: 480,
+            fx: 600.0,
+            fy: 610.0,
+            cx: 320.0,
+            cy: 240.0,
+            distortion_model: DistortionModel::RadialTangential,
+            distortion: Vec::new(),
+        }
+    }
+
+    /// Corners of the tag seen from the pose, as they are detected in the undistorted image.
+    fn project_corners(
+        calibration: &CameraCalibration,
+        rotation: &[[f64; 3]; 3],
+        translation: &[f64; 3],
+    ) -> [[f32; 2]; 4] {
+        let half = TAG_SIZE / 2.0;
+
+        TAG_CORNERS.map(|[x, y]| {
+            let point = transform(rotation, translation, &[x * half, y * half, 0.0]);
+            calibration.project(point).map(|v| v as f32)
+        })
+    }
+
+    fn assert_close<const N: usize>(actual: [f64; N], expected: [f64; N], tolerance: f64) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                (a - e).abs() < tolerance,
+                "{actual:?} differs from {expected:?} by more than {tolerance}"
+            );
+        }
+    }
+
+    fn assert_recovers_pose(rotation_vector: [f64; 3], translation: [f64; 3]) {
+        let calibration = calibration();
+        let rotation = vector_to_rotation(rotation_vector);
+        let corners = project_corners(&calibration, &rotation, &translation);
+
+        // The decomposition alone is only as precise as the f32 homography
+        let image_points = corners.map(|[x, y]| [x as f64, y as f64]);
+        let (initial_rotation, initial_translation) =
+            pose_from_homography(&image_points, &calibration, TAG_SIZE / 2.0)
+                .expect("The homography should be decomposed");
+        for (actual, expected) in initial_rotation.iter().zip(rotation) {
+            assert_close(*actual, expected, 1e-2);
+        }
+        assert_close(initial_translation, translation, 1e-2);
+
+        let pose = estimate_tag_pose(&corners, &calibration, TAG_SIZE)
+            .expect("The pose should be estimated");
+        for (actual, expected) in pose.rotation.iter().zip(rotation) {
+            assert_close(*actual, expected, 1e-4);
+        }
+        assert_close(pose.translation, translation, 1e-4);
+        assert!(pose.reprojection_error < 1e-3);
+    }
+
+    #[test]
+    fn recovers_near_frontal_pose() {
+        assert_recovers_pose([0.02, -0.01, 0.0], [0.02, -0.01, 0.5]);
+    }
+
+    #[test]
+    fn recovers_pose_rotated_in_plane() {
+        assert_recovers_pose([0.0, 0.0, 1.2], [-0.1, 0.05, 0.8]);
+    }
+
+    #[test]
+    fn recovers_tilted_pose() {
+        assert_recovers_pose([0.6, -0.4, 0.3], [0.05, 0.1, 0.6]);
+    }
+
+    #[test]
+    fn rotation_vector_round_trip() {
+        for vector in [[0.0, 0.0, 0.0], [0.3, -0.2, 0.1], [0.0, 0.0, 3.0]] {
+            assert_close(
+                rotation_to_vector(&vector_to_rotation(vector)),
+                vector,
+                1e-9,
+            );
+        }
+    }
+}
@@ -4,25 +4,31 @@ use kornia_image::{
     allocator::{CpuAllocator, ImageAllocator},
 };
 use kornia_imgproc::draw::draw_line;
-use std::hash::{DefaultHasher, Hash, Hasher};
 
-pub fn debug_quad_fitting<A: ImageAllocator>(
-    src: &Image<u8, 3, A>,
+use crate::{
+    annotate::copy_to_rgb,
+    palette::{BoundingBox, Palette},
+};
+
+/// Draws the quads onto a copy of `src`, grayscale frames are expanded to RGB.
+pub fn debug_quad_fitting<A: ImageAllocator, const C: usize>(
+    src: &Image<u8, C, A>,
     dst: &mut Image<u8, 3, CpuAllocator>,
     quads: &[Quad],
+    palette: &mut Palette,
 ) {
-    dst.as_slice_mut().copy_from_slice(src.as_slice());
+    copy_to_rgb(src, dst);
 
-    for (i, quad) in quads.iter().enumerate() {
-        // Generate Unique Color for each quad
-        let mut hasher = DefaultHasher::new();
-        i.hash(&mut hasher);
-        let hash = hasher.finish();
-        let color = [
-            ((hash >> 16) & 0xFF) as u8,
-            ((hash >> 8) & 0xFF) as u8,
-            (hash & 0xFF) as u8,
-        ];
+    for quad in quads {
+        // Corners left of or above the image saturate to 0
+        let Some(bbox) = BoundingBox::from_points(
+            quad.corners
+                .iter()
+                .map(|corner| [corner.x as usize, corner.y as usize]),
+        ) else {
+            continue;
+        };
+        let color = palette.color(bbox);
 
         let cords = [
             (quad.corners[0].x as i64, quad.corners[0].y as i64),
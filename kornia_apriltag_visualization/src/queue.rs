@@ -0,0 +1,381 @@
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Condvar, Mutex},
+    time::{Instant, SystemTime},
+};
+
+use kornia_image::{
+    Image,
+    allocator::{CpuAllocator, ImageAllocator},
+};
+
+use crate::source::{FrameHandler, FrameRef};
+
+/// Frame which is dropped when the [`FrameQueue`] is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Keeps the latest frames, which keeps the latency low
+    Oldest,
+    /// Keeps the queued frames, so the processed frames stay consecutive in bursts
+    Newest,
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest" => Ok(Self::Oldest),
+            "newest" => Ok(Self::Newest),
+            _ => Err(format!(
+                "Unknown drop policy {s}, expected oldest or newest"
+            )),
+        }
+    }
+}
+
+/// Frame copied out of the source, so it outlives the grab.
+///
+/// NOTE: This is the one copy of every grabbed frame. The GStreamer buffer of a camera frame
+/// must be released before the next grab, so it can't wait in the queue itself. The copy goes
+/// into a recycled buffer, so it doesn't allocate, and the pipeline reads the queued frame
+/// without copying it again.
+pub enum OwnedFrame {
+    Rgb(Image<u8, 3, CpuAllocator>),
+    Gray(Image<u8, 1, CpuAllocator>),
+}
+
+impl OwnedFrame {
+    pub fn as_frame_ref(&self) -> FrameRef<'_, CpuAllocator> {
+        match self {
+            OwnedFrame::Rgb(img) => FrameRef::Rgb(img),
+            OwnedFrame::Gray(img) => FrameRef::Gray(img),
+        }
+    }
+
+    /// Copies `frame`, reusing the buffer of `recycled` if it has the same format and size.
+    fn copy_from<A: ImageAllocator>(
+        frame: FrameRef<'_, A>,
+        recycled: Option<OwnedFrame>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        match (frame, recycled) {
+            (FrameRef::Rgb(img), Some(OwnedFrame::Rgb(mut buffer)))
+                if buffer.size() == img.size() =>
+            {
+                buffer.as_slice_mut().copy_from_slice(img.as_slice());
+                Ok(OwnedFrame::Rgb(buffer))
+            }
+            (FrameRef::Gray(img), Some(OwnedFrame::Gray(mut buffer)))
+                if buffer.size() == img.size() =>
+            {
+                buffer.as_slice_mut().copy_from_slice(img.as_slice());
+                Ok(OwnedFrame::Gray(buffer))
+            }
+            (FrameRef::Rgb(img), _) => Ok(OwnedFrame::Rgb(Image::from_size_slice(
+                img.size(),
+                img.as_slice(),
+                CpuAllocator,
+            )?)),
+            (FrameRef::Gray(img), _) => Ok(OwnedFrame::Gray(Image::from_size_slice(
+                img.size(),
+                img.as_slice(),
+                CpuAllocator,
+            )?)),
+        }
+    }
+}
+
+/// Frame waiting in the [`FrameQueue`].
+pub struct QueuedFrame {
+    /// Index of the frame in the order it was captured, gaps are dropped frames
+    pub seq: u64,
+    /// Used to measure the latency from the capture to the end of the processing
+    pub captured_at: Instant,
+    pub capture_time: SystemTime,
+    pub frame: OwnedFrame,
+}
+
+struct QueueState {
+    frames: VecDeque<QueuedFrame>,
+    /// Buffers of the processed and dropped frames, reused for the next frames
+    free: Vec<OwnedFrame>,
+    dropped: u64,
+    closed: bool,
+}
+
+/// Bounded ring buffer between the capture thread and the processing thread. When the
+/// processing can't keep up, frames are dropped according to the [`DropPolicy`] instead of
+/// piling up.
+pub struct FrameQueue {
+    capacity: usize,
+    policy: DropPolicy,
+    state: Mutex<QueueState>,
+    available: Condvar,
+}
+
+impl FrameQueue {
+    pub fn new(capacity: usize, policy: DropPolicy) -> Self {
+        let capacity = capacity.max(1);
+
+        Self {
+            capacity,
+            policy,
+            state: Mutex::new(QueueState {
+                frames: VecDeque::with_capacity(capacity),
+                free: Vec::with_capacity(capacity + 2),
+                dropped: 0,
+                closed: false,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Copies the frame into the queue, returns `false` if it was dropped.
+    pub fn push<A: ImageAllocator>(
+        &self,
+        seq: u64,
+        captured_at: Instant,
+        capture_time: SystemTime,
+        frame: FrameRef<'_, A>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let recycled = {
+            let mut state = self.lock();
+
+            // Don't even copy a frame which would be dropped
+            if self.policy == DropPolicy::Newest && state.frames.len() >= self.capacity {
+                state.dropped += 1;
+                return Ok(false);
+            }
+
+            state.free.pop()
+        };
+
+        // Copy outside of the lock, so the processing thread isn't blocked meanwhile
+        let frame = OwnedFrame::copy_from(frame, recycled)?;
+
+        let mut state = self.lock();
+
+        if state.frames.len() >= self.capacity {
+            match self.policy {
+                DropPolicy::Oldest => {
+                    if let Some(oldest) = state.frames.pop_front() {
+                        state.free.push(oldest.frame);
+                    }
+                }
+                // The queue filled up while copying
+                DropPolicy::Newest => {
+                    state.free.push(frame);
+                    state.dropped += 1;
+                    return Ok(false);
+                }
+            }
+
+            state.dropped += 1;
+        }
+
+        state.frames.push_back(QueuedFrame {
+            seq,
+            captured_at,
+            capture_time,
+            frame,
+        });
+        self.available.notify_one();
+
+        Ok(true)
+    }
+
+    /// Waits for the next frame, returns `None` once the queue is closed and empty.
+    pub fn pop(&self) -> Option<QueuedFrame> {
+        let mut state = self.lock();
+
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                return Some(frame);
+            }
+
+            if state.closed {
+                return None;
+            }
+
+            state = self
+                .available
+                .wait(state)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+    }
+
+    /// Hands the buffer of a processed frame back, so the next frame is copied into it.
+    pub fn recycle(&self, frame: OwnedFrame) {
+        let mut state = self.lock();
+
+        // Frames in the queue, plus the one being processed and the one being copied
+        if state.free.len() < self.capacity + 2 {
+            state.free.push(frame);
+        }
+    }
+
+    /// Number of frames dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+
+    /// Wakes up the processing thread and signals the capture thread to stop, called by either
+    /// side when it stops.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.available.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        // A panic of the other thread doesn't leave the state inconsistent
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Handler of the capture thread, copies every grabbed frame into the [`FrameQueue`].
+pub struct QueueProducer<'a> {
+    queue: &'a FrameQueue,
+    next_seq: u64,
+}
+
+impl<'a> QueueProducer<'a> {
+    pub fn new(queue: &'a FrameQueue) -> Self {
+        Self { queue, next_seq: 0 }
+    }
+}
+
+impl FrameHandler for QueueProducer<'_> {
+    fn handle<A: ImageAllocator>(
+        &mut self,
+        frame: FrameRef<'_, A>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.queue
+            .push(self.next_seq, Instant::now(), SystemTime::now(), frame)?;
+        self.next_seq += 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kornia_image::ImageSize;
+
+    const SIZE: ImageSize = ImageSize {
+        width: 4,
+        height: 2,
+    };
+
+    fn push(queue: &FrameQueue, seq: u64) -> bool {
+        let img = Image::from_size_val(SIZE, seq as u8, CpuAllocator).unwrap();
+        queue
+            .push(seq, Instant::now(), SystemTime::now(), FrameRef::Gray(&img))
+            .unwrap()
+    }
+
+    fn pop_seqs(queue: &FrameQueue) -> Vec<u64> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop())
+            .map(|queued| queued.seq)
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest_frames() {
+        let queue = FrameQueue::new(2, DropPolicy::Oldest);
+
+        assert!(push(&queue, 0));
+        assert!(push(&queue, 1));
+        assert!(push(&queue, 2));
+        assert!(push(&queue, 3));
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(pop_seqs(&queue), [2, 3]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_frames() {
+        let queue = FrameQueue::new(2, DropPolicy::Newest);
+
+        assert!(push(&queue, 0));
+        assert!(push(&queue, 1));
+        assert!(!push(&queue, 2));
+        assert!(!push(&queue, 3));
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(pop_seqs(&queue), [0, 1]);
+    }
+
+    #[test]
+    fn queued_frames_are_copies() {
+        let queue = FrameQueue::new(1, DropPolicy::Oldest);
+        push(&queue, 7);
+
+        let queued = queue.pop().unwrap();
+        let OwnedFrame::Gray(img) = &queued.frame else {
+            panic!("Expected a grayscale frame");
+        };
+        assert!(img.as_slice().iter().all(|&v| v == 7));
+    }
+
+    #[test]
+    fn recycled_buffers_are_reused_and_capped() {
+        let queue = FrameQueue::new(1, DropPolicy::Oldest);
+        push(&queue, 0);
+
+        let queued = queue.pop().unwrap();
+        let OwnedFrame::Gray(img) = &queued.frame else {
+            panic!("Expected a grayscale frame");
+        };
+        let buffer = img.as_slice().as_ptr();
+        queue.recycle(queued.frame);
+
+        push(&queue, 1);
+        let queued = queue.pop().unwrap();
+        let OwnedFrame::Gray(img) = &queued.frame else {
+            panic!("Expected a grayscale frame");
+        };
+        assert_eq!(img.as_slice().as_ptr(), buffer);
+        assert!(img.as_slice().iter().all(|&v| v == 1));
+
+        // One buffer for the queued frame, the processed one and the one being copied
+        for _ in 0..5 {
+            queue.recycle(OwnedFrame::Gray(
+                Image::from_size_val(SIZE, 0u8, CpuAllocator).unwrap(),
+            ));
+        }
+        assert_eq!(queue.lock().free.len(), 3);
+    }
+
+    #[test]
+    fn close_wakes_up_pop() {
+        let queue = FrameQueue::new(2, DropPolicy::Oldest);
+
+        std::thread::scope(|scope| {
+            let consumer = scope.spawn(|| queue.pop().map(|queued| queued.seq));
+
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            queue.close();
+
+            assert_eq!(consumer.join().unwrap(), None);
+        });
+        assert!(queue.is_closed());
+    }
+
+    #[test]
+    fn pop_drains_the_queue_after_close() {
+        let queue = FrameQueue::new(2, DropPolicy::Oldest);
+        push(&queue, 0);
+        push(&queue, 1);
+
+        assert_eq!(pop_seqs(&queue), [0, 1]);
+        assert!(queue.pop().is_none());
+    }
+}
@@ -1,33 +1,401 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use argh::FromArgs;
 use kornia_apriltag::{AprilTagDecoder, DecodeTagsConfig, family::TagFamilyKind};
-use kornia_image::{Image, allocator::CpuAllocator};
+use kornia_apriltag_visualization::{
+    annotate::{RenderOptions, Renderer, save_image},
+    calibration::CameraCalibration,
+    export::{DetectionExporter, FrameRecord},
+    family::parse_families,
+    pose::estimate_tag_pose,
+};
+use kornia_image::{Image, ImageSize, allocator::CpuAllocator};
 use kornia_imgproc::color::gray_from_rgb_u8;
-use kornia_io::jpeg::read_image_jpeg_rgb8;
+use kornia_io::{jpeg::read_image_jpeg_rgb8, png::read_image_png_rgb8};
+use rayon::prelude::*;
 
 #[cfg(feature = "dhat-heap")]
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
+/// Extensions of the images picked up from directories.
+const IMAGE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+#[derive(FromArgs)]
+/// Detect the tags in JPEG and PNG images, given as paths, directories or glob patterns, and
+/// print a summary of the detections.
+struct Args {
+    /// images, directories of images or glob patterns like "data/**/*.jpg" (default:
+    /// ./kornia_apriltag_visualization/data/tags_01.jpg)
+    #[argh(positional)]
+    inputs: Vec<String>,
+
+    /// tag family to detect, can be repeated (default: tag36h11)
+    #[argh(option)]
+    family: Vec<String>,
+
+    /// write the detections to this .jsonl or .csv file, can be repeated
+    #[argh(option)]
+    export: Vec<PathBuf>,
+
+    /// directory where the images are written with the detected tags drawn on top, under the
+    /// same relative paths as the inputs
+    #[argh(option, short = 'o')]
+    output_dir: Option<PathBuf>,
+
+    /// camera calibration (JSON or YAML) of the images, used to estimate the 3D pose of the tags
+    #[argh(option)]
+    calibration: Option<PathBuf>,
+
+    /// edge length of the black border of the tags in meters
+    #[argh(option, default = "0.1")]
+    tag_size: f64,
+
+    /// number of worker threads (default: number of cores)
+    #[argh(option, short = 'j')]
+    threads: Option<usize>,
+
+    /// print every detection instead of one line per image
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+}
+
+/// Decoder and buffers of a worker thread, kept as long as the images have the same size.
+struct Worker {
+    size: ImageSize,
+    decoder: AprilTagDecoder,
+    grayscale: Image<u8, 1, CpuAllocator>,
+}
+
+/// Settings shared by all the workers.
+struct Context {
+    families: Vec<TagFamilyKind>,
+    calibration: Option<CameraCalibration>,
+    tag_size: f64,
+    renderer: Renderer,
+}
+
+/// Result of a single image.
+struct ImageResult {
+    path: PathBuf,
+    record: Option<FrameRecord>,
+    elapsed: Duration,
+    error: Option<String>,
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(feature = "dhat-heap")]
     let _profiler = dhat::Profiler::new_heap();
 
-    let img = read_image_jpeg_rgb8("./kornia_apriltag_visualization/data/tags_01.jpg")?;
+    let args: Args = argh::from_env();
+    let families = parse_families(&args.family)?;
+    let calibration = args
+        .calibration
+        .as_ref()
+        .map(CameraCalibration::load)
+        .transpose()?;
 
-    let mut grayscale = Image::from_size_val(img.size(), 0u8, CpuAllocator)?;
-    gray_from_rgb_u8(&img, &mut grayscale)?;
+    // The poses are estimated from the undistorted corners, but drawn onto the distorted image
+    let mut renderer = Renderer::new(RenderOptions::default());
+    if let Some(calibration) = &calibration {
+        renderer = renderer.with_calibration(calibration.clone(), true);
+    }
+    let context = Context {
+        families,
+        calibration,
+        tag_size: args.tag_size,
+        renderer,
+    };
+
+    let inputs = if args.inputs.is_empty() {
+        vec!["./kornia_apriltag_visualization/data/tags_01.jpg".to_string()]
+    } else {
+        args.inputs.clone()
+    };
+    let paths = collect_images(&inputs)?;
+
+    if paths.is_empty() {
+        return Err("No images found".into());
+    }
+
+    let outputs = args
+        .output_dir
+        .as_ref()
+        .map(|output_dir| output_paths(output_dir, &paths));
+
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+
+    let start = Instant::now();
+    let results = paths
+        .par_iter()
+        .enumerate()
+        // The decoder is created for the first image of every rayon job, and reused by the rest
+        .map_init(
+            || None,
+            |worker, (frame_idx, path)| {
+                let start = Instant::now();
+                let output = outputs.as_ref().map(|outputs| outputs[frame_idx].as_path());
+                let result = process_image(worker, &context, frame_idx as u64, path, output);
+
+                let (record, error) = match result {
+                    Ok(record) => (Some(record), None),
+                    Err(err) => (None, Some(err.to_string())),
+                };
+
+                ImageResult {
+                    path: path.clone(),
+                    record,
+                    elapsed: start.elapsed(),
+                    error,
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+    let total_elapsed = start.elapsed();
+
+    print_summary(&results, total_elapsed, args.verbose);
+
+    for path in &args.export {
+        let mut exporter = DetectionExporter::create(path)?;
+        for record in results.iter().filter_map(|result| result.record.as_ref()) {
+            exporter.write(record)?;
+        }
+        exporter.flush()?;
+
+        println!("Exported the detections to {}", path.display());
+    }
+
+    Ok(())
+}
 
-    let config = DecodeTagsConfig::new(vec![TagFamilyKind::Tag36H11]);
-    let mut decoder = AprilTagDecoder::new(config, grayscale.size())?;
+/// Expands the inputs into the list of images, sorted within every directory and pattern.
+fn collect_images(inputs: &[String]) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
 
-    let detection = decoder.decode(&grayscale)?;
-    decoder.clear();
+    for input in inputs {
+        if input.contains(['*', '?', '[']) {
+            let mut matches = glob::glob(input)?
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|path| path.is_file())
+                .collect::<Vec<_>>();
+            matches.sort();
 
-    for (i, tag) in detection.iter().enumerate() {
+            if matches.is_empty() {
+                println!("No images match {input}");
+            }
+            paths.extend(matches);
+            continue;
+        }
+
+        let path = PathBuf::from(input);
+        if path.is_dir() {
+            let mut images = std::fs::read_dir(&path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|path| is_image(path))
+                .collect::<Vec<_>>();
+            images.sort();
+
+            paths.extend(images);
+        } else if path.is_file() {
+            paths.push(path);
+        } else {
+            return Err(format!("{input} doesn't exist").into());
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Paths the annotated images are written to, mirroring the paths of the inputs, so images with
+/// the same name in different directories don't overwrite each other.
+fn output_paths(output_dir: &Path, paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut used = HashSet::new();
+
+    paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            // Only the normal components, so `..` and absolute paths stay in the output directory
+            let relative = path
+                .components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .collect::<PathBuf>();
+            let mut output = output_dir.join(&relative);
+
+            // Paths which still collide, e.g. `data/a.jpg` and `../data/a.jpg`, get the index
+            if !used.insert(output.clone()) {
+                let stem = relative.file_stem().unwrap_or_default().to_string_lossy();
+                let name = match relative.extension() {
+                    Some(ext) => format!("{stem}_{i}.{}", ext.to_string_lossy()),
+                    None => format!("{stem}_{i}"),
+                };
+                output.set_file_name(name);
+                used.insert(output.clone());
+            }
+
+            output
+        })
+        .collect()
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Detects the tags in the image, and writes it with the tags drawn on top to `output`.
+fn process_image(
+    worker: &mut Option<Worker>,
+    context: &Context,
+    frame_idx: u64,
+    path: &Path,
+    output: Option<&Path>,
+) -> Result<FrameRecord, Box<dyn std::error::Error>> {
+    let is_png = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    let mut img = if is_png {
+        read_image_png_rgb8(path)?
+    } else {
+        read_image_jpeg_rgb8(path)?
+    };
+
+    // The buffers of the decoder are sized for the image
+    let worker = match worker.take() {
+        Some(previous) if previous.size == img.size() => worker.insert(previous),
+        _ => worker.insert(Worker {
+            size: img.size(),
+            decoder: AprilTagDecoder::new(
+                DecodeTagsConfig::new(context.families.clone()),
+                img.size(),
+            )?,
+            grayscale: Image::from_size_val(img.size(), 0u8, CpuAllocator)?,
+        }),
+    };
+
+    gray_from_rgb_u8(&img, &mut worker.grayscale)?;
+    let detections = worker.decoder.decode(&worker.grayscale)?;
+    worker.decoder.clear();
+
+    let poses = match &context.calibration {
+        Some(calibration) => detections
+            .iter()
+            .map(|tag| {
+                let corners = tag.quad.corners.map(|corner| {
+                    calibration
+                        .undistort_pixel([corner.x as f64, corner.y as f64])
+                        .map(|v| v as f32)
+                });
+                estimate_tag_pose(&corners, calibration, context.tag_size)
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    if let Some(output) = output {
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        context.renderer.render(&mut img, &detections, &poses);
+        save_image(output, &img)?;
+    }
+
+    // Images have no capture time, use the time they were last modified
+    let capture_time = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or_else(|_| SystemTime::now());
+
+    Ok(FrameRecord::new(
+        frame_idx,
+        capture_time,
+        Some(path.display().to_string()),
+        &detections,
+        &poses,
+    ))
+}
+
+fn print_summary(results: &[ImageResult], total_elapsed: Duration, verbose: bool) {
+    let path_width = results
+        .iter()
+        .map(|result| result.path.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("image".len());
+
+    println!(
+        "{:<path_width$}  {:>5}  {:>9}  ids",
+        "image", "tags", "time [ms]"
+    );
+
+    let mut family_counts = BTreeMap::<&str, usize>::new();
+    let mut failed = 0;
+
+    for result in results {
+        let path = result.path.display();
+        let elapsed_ms = result.elapsed.as_secs_f64() * 1000.0;
+
+        let Some(record) = &result.record else {
+            failed += 1;
+            println!(
+                "{path:<path_width$}  {:>5}  {elapsed_ms:>9.1}  error: {}",
+                "-",
+                result.error.as_deref().unwrap_or_default()
+            );
+            continue;
+        };
+
+        for tag in &record.detections {
+            *family_counts.entry(tag.family).or_default() += 1;
+        }
+
+        let ids = record
+            .detections
+            .iter()
+            .map(|tag| format!("{}:{}", tag.family, tag.id))
+            .collect::<Vec<_>>()
+            .join(" ");
         println!(
-            "{}: id: {}, center {:?}, quad: {:?}, decision_margin: {}",
-            i, tag.id, tag.center, tag.quad.corners, tag.decision_margin
+            "{path:<path_width$}  {:>5}  {elapsed_ms:>9.1}  {ids}",
+            record.detections.len()
         );
+
+        if verbose {
+            for tag in &record.detections {
+                println!(
+                    "    {}:{} center {:?}, corners {:?}, hamming {}, decision_margin {:.1}",
+                    tag.family, tag.id, tag.center, tag.corners, tag.hamming, tag.decision_margin
+                );
+            }
+        }
     }
 
-    Ok(())
+    let images = results.len();
+    let processing_ms = results
+        .iter()
+        .map(|result| result.elapsed.as_secs_f64() * 1000.0)
+        .sum::<f64>();
+
+    println!();
+    println!(
+        "{images} images, {failed} failed, {} tags in {:.2} s ({:.1} ms per image and worker)",
+        family_counts.values().sum::<usize>(),
+        total_elapsed.as_secs_f64(),
+        processing_ms / images.max(1) as f64
+    );
+    for (family, count) in family_counts {
+        println!("    {family}: {count} tags");
+    }
 }
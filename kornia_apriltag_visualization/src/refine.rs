@@ -0,0 +1,177 @@
+use kornia_apriltag::{quad::Quad, utils::Point2d};
+use kornia_image::{Image, allocator::CpuAllocator};
+
+/// Minimum number of points sampled along every edge.
+const MIN_EDGE_SAMPLES: usize = 16;
+/// Step along the edge normal when searching for the strongest gradient.
+const NORMAL_STEP: f32 = 0.25;
+
+/// Re-fits the edges of the quad against the gradients of the full resolution image and moves
+/// the corners to the intersections of the fitted lines. Port of `refine_edges` from apriltag-c.
+///
+/// `search_range` is how far in pixels the edge is searched along its normal, it should cover the
+/// error of the corners, e.g. the decimation factor plus one.
+pub fn refine_edges(src: &Image<u8, 1, CpuAllocator>, quad: &mut Quad, search_range: f32) {
+    let corners = quad.corners.map(|corner| [corner.x, corner.y]);
+    let centroid = [
+        corners.iter().map(|c| c[0]).sum::<f32>() / 4.0,
+        corners.iter().map(|c| c[1]).sum::<f32>() / 4.0,
+    ];
+
+    // Outward normals of the edges, edge `i` goes from corner `i` to corner `i + 1`
+    let mut normals = [[0.0f32; 2]; 4];
+    for (i, normal) in normals.iter_mut().enumerate() {
+        let a = corners[i];
+        let b = corners[(i + 1) % 4];
+        let length = (b[0] - a[0]).hypot(b[1] - a[1]);
+
+        if length < 1e-3 {
+            return;
+        }
+
+        *normal = [(b[1] - a[1]) / length, (a[0] - b[0]) / length];
+
+        let mid = [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0];
+        if (mid[0] - centroid[0]) * normal[0] + (mid[1] - centroid[1]) * normal[1] < 0.0 {
+            *normal = [-normal[0], -normal[1]];
+        }
+    }
+
+    // Normal borders are white outside and black inside, reversed borders the other way around.
+    // Flip the normals so they point to the bright side of every edge.
+    let contrast = (0..4)
+        .map(|i| {
+            let a = corners[i];
+            let b = corners[(i + 1) % 4];
+            let mid = [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0];
+            let outside = sample(src, mid[0] + normals[i][0], mid[1] + normals[i][1]);
+            let inside = sample(src, mid[0] - normals[i][0], mid[1] - normals[i][1]);
+
+            outside.unwrap_or_default() - inside.unwrap_or_default()
+        })
+        .sum::<f32>();
+
+    if contrast < 0.0 {
+        for normal in &mut normals {
+            *normal = [-normal[0], -normal[1]];
+        }
+    }
+
+    // Lines as a point on the line and the line normal
+    let mut lines = [[0.0f32; 4]; 4];
+
+    for (i, line) in lines.iter_mut().enumerate() {
+        let a = corners[i];
+        let b = corners[(i + 1) % 4];
+        let [nx, ny] = normals[i];
+        let length = (b[0] - a[0]).hypot(b[1] - a[1]);
+        let samples = MIN_EDGE_SAMPLES.max((length / 8.0) as usize);
+
+        let (mut mx, mut my, mut mxx, mut mxy, mut myy, mut count) =
+            (0.0, 0.0, 0.0, 0.0, 0.0, 0.0f32);
+
+        for s in 0..samples {
+            // Skip the ends of the edge, the corners might be off
+            let alpha = (1 + s) as f32 / (samples + 1) as f32;
+            let x0 = alpha * a[0] + (1.0 - alpha) * b[0];
+            let y0 = alpha * a[1] + (1.0 - alpha) * b[1];
+
+            let mut weighted_offset = 0.0;
+            let mut weight_sum = 0.0;
+
+            let mut next = -search_range;
+            while next <= search_range {
+                let n = next;
+                next += NORMAL_STEP;
+
+                let bright = sample(src, x0 + (n + 1.0) * nx, y0 + (n + 1.0) * ny);
+                let dark = sample(src, x0 + (n - 1.0) * nx, y0 + (n - 1.0) * ny);
+
+                let (Some(bright), Some(dark)) = (bright, dark) else {
+                    continue;
+                };
+
+                // Gradients in the wrong direction belong to other edges
+                if bright < dark {
+                    continue;
+                }
+
+                let weight = (bright - dark) * (bright - dark);
+                weighted_offset += weight * n;
+                weight_sum += weight;
+            }
+
+            if weight_sum == 0.0 {
+                continue;
+            }
+
+            let offset = weighted_offset / weight_sum;
+            let x = x0 + offset * nx;
+            let y = y0 + offset * ny;
+
+            mx += x;
+            my += y;
+            mxx += x * x;
+            mxy += x * y;
+            myy += y * y;
+            count += 1.0;
+        }
+
+        if count < 2.0 {
+            return;
+        }
+
+        // Fit the line through the edge points, its normal is the direction of least variance
+        let ex = mx / count;
+        let ey = my / count;
+        let cxx = mxx / count - ex * ex;
+        let cxy = mxy / count - ex * ey;
+        let cyy = myy / count - ey * ey;
+        let normal_theta = 0.5 * (-2.0 * cxy).atan2(cyy - cxx);
+
+        *line = [ex, ey, normal_theta.cos(), normal_theta.sin()];
+    }
+
+    // Corner `i + 1` is the intersection of the edges `i` and `i + 1`
+    for i in 0..4 {
+        let l0 = lines[i];
+        let l1 = lines[(i + 1) % 4];
+
+        let a00 = l0[3];
+        let a01 = -l1[3];
+        let a10 = -l0[2];
+        let a11 = l1[2];
+        let b0 = l1[0] - l0[0];
+        let b1 = l1[1] - l0[1];
+
+        let det = a00 * a11 - a10 * a01;
+        if det.abs() <= 0.001 {
+            continue;
+        }
+
+        let l = (a11 * b0 - a01 * b1) / det;
+        quad.corners[(i + 1) % 4] = Point2d {
+            x: l0[0] + l * a00,
+            y: l0[1] + l * a10,
+        };
+    }
+}
+
+/// Distance every corner moved during the refinement.
+pub fn corner_corrections(before: &[Point2d<f32>; 4], after: &[Point2d<f32>; 4]) -> [f32; 4] {
+    [0, 1, 2, 3].map(|i| (after[i].x - before[i].x).hypot(after[i].y - before[i].y))
+}
+
+/// Pixel value at the position, `None` if it's outside of the image.
+fn sample(src: &Image<u8, 1, CpuAllocator>, x: f32, y: f32) -> Option<f32> {
+    if x < 0.0 || y < 0.0 {
+        return None;
+    }
+
+    let (x, y) = (x as usize, y as usize);
+    if x >= src.width() || y >= src.height() {
+        return None;
+    }
+
+    Some(src.as_slice()[y * src.width() + x] as f32)
+}
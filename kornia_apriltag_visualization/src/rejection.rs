@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use kornia_apriltag::{
+    DecodeTagsConfig, quad::fit_quads, segmentation::GradientInfo, utils::Pixel,
+};
+use kornia_image::{Image, allocator::ImageAllocator};
+
+/// Reason why a gradient cluster didn't result in a quad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum RejectionReason {
+    /// Fewer pixels than `FitQuadConfig::min_cluster_pixels`
+    TooFewPixels = 1,
+    /// More pixels than the perimeter of the image
+    TooManyPixels,
+    /// Bounding box smaller than `DecodeTagsConfig::min_tag_width`
+    TooSmall,
+    /// White inside black border, but `DecodeTagsConfig::reversed_border` is disabled
+    ReversedBorder,
+    /// Black inside white border, but `DecodeTagsConfig::normal_border` is disabled
+    NormalBorder,
+    /// Line fitting failed, or the fitted quad didn't pass the corner angle, area and convexity checks
+    LineFit,
+}
+
+impl RejectionReason {
+    pub const ALL: [RejectionReason; 6] = [
+        RejectionReason::TooFewPixels,
+        RejectionReason::TooManyPixels,
+        RejectionReason::TooSmall,
+        RejectionReason::ReversedBorder,
+        RejectionReason::NormalBorder,
+        RejectionReason::LineFit,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            RejectionReason::TooFewPixels => "too few pixels",
+            RejectionReason::TooManyPixels => "too many pixels",
+            RejectionReason::TooSmall => "too small",
+            RejectionReason::ReversedBorder => "reversed border",
+            RejectionReason::NormalBorder => "normal border",
+            RejectionReason::LineFit => "line fit",
+        }
+    }
+
+    pub fn color(&self) -> [u8; 3] {
+        match self {
+            RejectionReason::TooFewPixels => [128, 128, 128],
+            RejectionReason::TooManyPixels => [255, 0, 255],
+            RejectionReason::TooSmall => [255, 255, 0],
+            RejectionReason::ReversedBorder => [0, 128, 255],
+            RejectionReason::NormalBorder => [0, 255, 255],
+            RejectionReason::LineFit => [255, 0, 0],
+        }
+    }
+}
+
+/// Gradient cluster which was dropped by `fit_quads`.
+#[derive(Debug, Clone, Copy)]
+pub struct RejectedCluster {
+    pub reason: RejectionReason,
+    /// Top left corner of the bounding box in image coordinates
+    pub min: [f32; 2],
+    /// Bottom right corner of the bounding box in image coordinates
+    pub max: [f32; 2],
+    pub pixels: usize,
+}
+
+/// Runs `fit_quads` on every cluster separately to find the ones which are rejected, and
+/// repeats the cheap checks done before line fitting to find the reason of the rejection.
+///
+/// NOTE: The clusters are drained.
+pub fn find_rejected_clusters<A: ImageAllocator>(
+    src: &Image<Pixel, 1, A>,
+    clusters: &mut HashMap<(usize, usize), Vec<GradientInfo>>,
+    config: &DecodeTagsConfig,
+) -> Vec<RejectedCluster> {
+    let max_cluster_pixels = 4 * (src.width() + src.height());
+    let mut single_cluster = HashMap::with_capacity(1);
+    let mut rejected = Vec::new();
+
+    for (key, infos) in clusters.drain() {
+        // The gradient clusters are in twice the resolution of the image
+        let (mut min_x, mut min_y) = (usize::MAX, usize::MAX);
+        let (mut max_x, mut max_y) = (0, 0);
+
+        for info in &infos {
+            min_x = min_x.min(info.pos.x);
+            min_y = min_y.min(info.pos.y);
+            max_x = max_x.max(info.pos.x);
+            max_y = max_y.max(info.pos.y);
+        }
+
+        let pixels = infos.len();
+        let reason = if pixels < config.fit_quad_config.min_cluster_pixels {
+            Some(RejectionReason::TooFewPixels)
+        } else if pixels > max_cluster_pixels {
+            Some(RejectionReason::TooManyPixels)
+        } else if (max_x - min_x) * (max_y - min_y) < config.min_tag_width {
+            Some(RejectionReason::TooSmall)
+        } else {
+            let reversed = is_reversed_border(&infos, (min_x, min_y), (max_x, max_y));
+
+            if reversed && !config.reversed_border {
+                Some(RejectionReason::ReversedBorder)
+            } else if !reversed && !config.normal_border {
+                Some(RejectionReason::NormalBorder)
+            } else {
+                single_cluster.insert(key, infos);
+                let fitted = !fit_quads(src, &mut single_cluster, config).is_empty();
+                single_cluster.clear();
+
+                (!fitted).then_some(RejectionReason::LineFit)
+            }
+        };
+
+        if let Some(reason) = reason {
+            rejected.push(RejectedCluster {
+                reason,
+                min: [min_x as f32 / 2.0, min_y as f32 / 2.0],
+                max: [max_x as f32 / 2.0, max_y as f32 / 2.0],
+                pixels,
+            });
+        }
+    }
+
+    rejected
+}
+
+/// Checks if the gradients of the cluster point towards its center, i.e. white inside and black
+/// outside, same as the check in apriltag-c.
+fn is_reversed_border(
+    infos: &[GradientInfo],
+    (min_x, min_y): (usize, usize),
+    (max_x, max_y): (usize, usize),
+) -> bool {
+    let cx = (min_x + max_x) as f32 * 0.5 + 0.05118;
+    let cy = (min_y + max_y) as f32 * 0.5 - 0.028581;
+
+    let dot = infos.iter().fold(0.0, |acc, info| {
+        let dx = info.pos.x as f32 - cx;
+        let dy = info.pos.y as f32 - cy;
+
+        acc + dx * info.gx as f32 + dy * info.gy as f32
+    });
+
+    dot < 0.0
+}
+
+/// Logs the annotation context mapping every [`RejectionReason`] to its label and color.
+pub fn log_rejection_annotation_context(
+    rec: &rerun::RecordingStream,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let classes = RejectionReason::ALL.map(|reason| {
+        let [r, g, b] = reason.color();
+        (
+            reason as u16,
+            reason.label(),
+            rerun::Rgba32::from_rgb(r, g, b),
+        )
+    });
+
+    rec.log_static(path, &rerun::AnnotationContext::new(classes))?;
+
+    Ok(())
+}
+
+pub fn log_rejected_clusters(
+    rec: &rerun::RecordingStream,
+    path: &str,
+    rejected: &[RejectedCluster],
+) -> Result<(), Box<dyn std::error::Error>> {
+    rec.log(
+        path,
+        &rerun::Boxes2D::from_mins_and_sizes(
+            rejected.iter().map(|cluster| cluster.min),
+            rejected.iter().map(|cluster| {
+                [
+                    cluster.max[0] - cluster.min[0],
+                    cluster.max[1] - cluster.min[1],
+                ]
+            }),
+        )
+        .with_class_ids(rejected.iter().map(|cluster| cluster.reason as u16))
+        .with_labels(
+            rejected
+                .iter()
+                .map(|cluster| format!("{} ({} px)", cluster.reason.label(), cluster.pixels)),
+        ),
+    )?;
+
+    Ok(())
+}
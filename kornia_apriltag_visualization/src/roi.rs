@@ -0,0 +1,169 @@
+use std::path::Path;
+
+use kornia_image::{Image, ImageSize, allocator::CpuAllocator};
+use kornia_io::png::read_image_png_mono8;
+
+/// Size of the blocks a mask is split into.
+const MASK_BLOCK_SIZE: usize = 32;
+
+/// Axis aligned region of the image in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Roi {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+/// Region of an image in whole pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelRoi {
+    pub x: usize,
+    pub y: usize,
+    pub size: ImageSize,
+}
+
+impl Roi {
+    pub fn width(&self) -> f32 {
+        self.max[0] - self.min[0]
+    }
+
+    pub fn height(&self) -> f32 {
+        self.max[1] - self.min[1]
+    }
+
+    fn intersects(&self, other: &Roi) -> bool {
+        self.min[0] <= other.max[0]
+            && other.min[0] <= self.max[0]
+            && self.min[1] <= other.max[1]
+            && other.min[1] <= self.max[1]
+    }
+
+    fn union(&self, other: &Roi) -> Roi {
+        Roi {
+            min: [self.min[0].min(other.min[0]), self.min[1].min(other.min[1])],
+            max: [self.max[0].max(other.max[0]), self.max[1].max(other.max[1])],
+        }
+    }
+
+    /// Scales the region by `1 / scale` and converts it into whole pixels of an image of `size`.
+    /// The size is rounded up to a multiple of `align`, so it can be split into tiles. Returns
+    /// `None` if the region doesn't cover at least one tile of the image.
+    pub fn to_pixels(&self, size: ImageSize, scale: f32, align: usize) -> Option<PixelRoi> {
+        let x0 = (self.min[0] / scale).floor().max(0.0) as usize;
+        let y0 = (self.min[1] / scale).floor().max(0.0) as usize;
+        let x1 = ((self.max[0] / scale).ceil().max(0.0) as usize).min(size.width);
+        let y1 = ((self.max[1] / scale).ceil().max(0.0) as usize).min(size.height);
+
+        let width = x1.checked_sub(x0)?.next_multiple_of(align);
+        let height = y1.checked_sub(y0)?.next_multiple_of(align);
+
+        // Grow to the top left if the aligned region doesn't fit anymore
+        let width = width.min(size.width - size.width % align);
+        let height = height.min(size.height - size.height % align);
+        let x = x0.min(size.width - width);
+        let y = y0.min(size.height - height);
+
+        (width > 0 && height > 0).then_some(PixelRoi {
+            x,
+            y,
+            size: ImageSize { width, height },
+        })
+    }
+}
+
+impl PixelRoi {
+    fn intersects(&self, other: &PixelRoi) -> bool {
+        self.x < other.x + other.size.width
+            && other.x < self.x + self.size.width
+            && self.y < other.y + other.size.height
+            && other.y < self.y + self.size.height
+    }
+
+    /// Region covering both regions, not aligned.
+    fn union(&self, other: &PixelRoi) -> Roi {
+        let min = [self.x.min(other.x), self.y.min(other.y)];
+        let max = [
+            (self.x + self.size.width).max(other.x + other.size.width),
+            (self.y + self.size.height).max(other.y + other.size.height),
+        ];
+
+        Roi {
+            min: min.map(|v| v as f32),
+            max: max.map(|v| v as f32),
+        }
+    }
+}
+
+/// Merges the overlapping regions, so no tag is searched twice.
+pub fn merge_rois(rois: &[Roi]) -> Vec<Roi> {
+    let mut merged: Vec<Roi> = Vec::with_capacity(rois.len());
+
+    for roi in rois {
+        let mut roi = *roi;
+
+        // A merged region can overlap regions merged before, so repeat until nothing changes
+        while let Some(i) = merged.iter().position(|other| other.intersects(&roi)) {
+            roi = roi.union(&merged.swap_remove(i));
+        }
+
+        merged.push(roi);
+    }
+
+    merged
+}
+
+/// Loads a mask where non-zero pixels mark the regions to search for tags in.
+pub fn load_roi_mask(path: impl AsRef<Path>) -> Result<Vec<Roi>, Box<dyn std::error::Error>> {
+    let mask = read_image_png_mono8(path)?;
+    Ok(rois_from_mask(&mask))
+}
+
+/// Covers the non-zero pixels of the mask with blocks, merged into regions.
+pub fn rois_from_mask(mask: &Image<u8, 1, CpuAllocator>) -> Vec<Roi> {
+    let mut blocks = Vec::new();
+
+    for y in (0..mask.height()).step_by(MASK_BLOCK_SIZE) {
+        for x in (0..mask.width()).step_by(MASK_BLOCK_SIZE) {
+            let x1 = (x + MASK_BLOCK_SIZE).min(mask.width());
+            let y1 = (y + MASK_BLOCK_SIZE).min(mask.height());
+
+            let covered = (y..y1).any(|row| {
+                mask.as_slice()[row * mask.width() + x..row * mask.width() + x1]
+                    .iter()
+                    .any(|&v| v > 0)
+            });
+
+            if covered {
+                blocks.push(Roi {
+                    min: [x as f32, y as f32],
+                    max: [x1 as f32, y1 as f32],
+                });
+            }
+        }
+    }
+
+    merge_rois(&blocks)
+}
+
+/// Merges the overlapping regions of an image of `size` in place. Aligning the regions grows
+/// them, so regions which were apart can overlap afterwards and the same tag would be decoded
+/// twice. The merged regions are aligned to `align` again, which can make them overlap further
+/// regions, so this repeats until no regions overlap.
+pub fn merge_pixel_rois(rois: &mut Vec<PixelRoi>, size: ImageSize, align: usize) {
+    let mut i = 0;
+
+    while i < rois.len() {
+        let Some(j) = (i + 1..rois.len()).find(|&j| rois[i].intersects(&rois[j])) else {
+            i += 1;
+            continue;
+        };
+
+        let other = rois.swap_remove(j);
+        rois[i] = rois[i]
+            .union(&other)
+            .to_pixels(size, 1.0, align)
+            .expect("The union of two regions inside the image covers at least one tile");
+
+        // The grown region can overlap the regions before it
+        i = 0;
+    }
+}
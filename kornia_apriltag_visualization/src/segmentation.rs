@@ -1,65 +1,259 @@
 use kornia_apriltag::segmentation::GradientInfo;
 use kornia_apriltag::union_find::UnionFind;
-use kornia_image::{Image, allocator::CpuAllocator};
-use std::collections::HashMap;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use kornia_image::ImageSize;
+use std::{cmp::Reverse, collections::HashMap};
+
+use crate::palette::{BoundingBox, Palette};
 
 const MIN_CLUSTER_PIXELS: usize = 5;
-const BLACK_COLOR: [u8; 3] = [0, 0, 0];
+/// Largest clusters which are described in the annotation context, the smaller ones are only
+/// colored by the viewer. A noisy frame has tens of thousands of clusters, and their labels would
+/// be logged with every frame.
+const MAX_LABELED_CLASSES: usize = 256;
+/// Class id of the pixels which belong to no cluster.
+pub const BACKGROUND_CLASS_ID: u16 = 0;
+const BACKGROUND_COLOR: [u8; 3] = [0, 0, 0];
+/// Index of no cluster in `Segmentation::ids` and `Segmentation::pixel_classes`
+const NO_CLASS: u32 = u32::MAX;
 
-pub fn debug_connected_components(dst: &mut Image<u8, 3, CpuAllocator>, uf: &mut UnionFind) {
-    let dst_slice = dst.as_slice_mut();
+/// Cluster of a [`Segmentation`], logged as a class of its annotation context.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentClass {
+    pub bbox: BoundingBox,
+    pub pixels: usize,
+    /// Color from the palette, only picked for the classes in the annotation context
+    pub color: [u8; 3],
+}
 
-    for i in 0..dst_slice.len() / 3 {
-        let representative = uf.get_representative(i);
-        let i = i * 3;
+/// Class id of every pixel, logged as a rerun `SegmentationImage` along with an annotation
+/// context, so hovering a cluster in the viewer shows its id, size and bounding box.
+/// Class `i + 1` is the cluster `classes[i]`, the pixels of no cluster are
+/// [`BACKGROUND_CLASS_ID`].
+#[derive(Debug)]
+pub struct Segmentation {
+    size: ImageSize,
+    /// Name of the clusters in the labels, e.g. "component"
+    name: &'static str,
+    class_ids: Vec<u16>,
+    classes: Vec<SegmentClass>,
+    /// Index into `classes` of every pixel, before the classes are sorted
+    pixel_classes: Vec<u32>,
+    /// Index into `classes` of every cluster key, before the classes are sorted
+    ids: Vec<u32>,
+    /// Class id of every cluster by its index in `classes` before the sorting
+    ranks: Vec<u16>,
+    /// Indices into `classes` with the largest cluster first
+    order: Vec<usize>,
+    /// The sorted classes, swapped with `classes` once they are assigned
+    sorted: Vec<SegmentClass>,
+}
+
+impl Segmentation {
+    pub fn new(size: ImageSize, name: &'static str) -> Self {
+        Self {
+            size,
+            name,
+            class_ids: vec![BACKGROUND_CLASS_ID; size.width * size.height],
+            classes: Vec::new(),
+            pixel_classes: vec![NO_CLASS; size.width * size.height],
+            // The keys are usually pixel indices, e.g. the representatives of the components
+            ids: vec![NO_CLASS; size.width * size.height],
+            ranks: Vec::new(),
+            order: Vec::new(),
+            sorted: Vec::new(),
+        }
+    }
+
+    /// Logs the largest classes of this frame as the annotation context of the segmentation
+    /// image, followed by the image.
+    pub fn log(
+        &self,
+        rec: &rerun::RecordingStream,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let [r, g, b] = BACKGROUND_COLOR;
+        let background = rerun::datatypes::ClassDescription {
+            info: rerun::datatypes::AnnotationInfo {
+                id: BACKGROUND_CLASS_ID,
+                label: Some("background".into()),
+                color: Some(rerun::Rgba32::from_rgb(r, g, b)),
+            },
+            keypoint_annotations: Vec::new(),
+            keypoint_connections: Vec::new(),
+        };
+
+        let classes = self
+            .classes
+            .iter()
+            .take(MAX_LABELED_CLASSES)
+            .enumerate()
+            .map(|(i, class)| {
+                let [r, g, b] = class.color;
+                let bbox = &class.bbox;
+
+                rerun::datatypes::ClassDescription {
+                    info: rerun::datatypes::AnnotationInfo {
+                        id: i as u16 + 1,
+                        label: Some(
+                            format!(
+                                "{} {}: {} px, {}x{} at ({}, {})",
+                                self.name,
+                                i + 1,
+                                class.pixels,
+                                bbox.width(),
+                                bbox.height(),
+                                bbox.min[0],
+                                bbox.min[1]
+                            )
+                            .into(),
+                        ),
+                        color: Some(rerun::Rgba32::from_rgb(r, g, b)),
+                    },
+                    keypoint_annotations: Vec::new(),
+                    keypoint_connections: Vec::new(),
+                }
+            });
 
+        // The classes change every frame, so the context is logged with every image, which is why
+        // only the largest classes are in it
+        rec.log(
+            path,
+            &rerun::AnnotationContext::new(std::iter::once(background).chain(classes)),
+        )?;
+        rec.log(
+            path,
+            &rerun::SegmentationImage::from_elements(&self.class_ids, self.size.into()),
+        )?;
+
+        Ok(())
+    }
+
+    /// Starts a new frame, all the pixels are background.
+    fn clear(&mut self) {
+        self.class_ids.fill(BACKGROUND_CLASS_ID);
+        self.classes.clear();
+        self.pixel_classes.fill(NO_CLASS);
+        self.ids.fill(NO_CLASS);
+    }
+
+    fn is_assigned(&self, x: usize, y: usize) -> bool {
+        self.pixel_classes[y * self.size.width + x] != NO_CLASS
+    }
+
+    /// Adds the pixel to the cluster with the key.
+    fn add(&mut self, key: usize, x: usize, y: usize) {
+        if key >= self.ids.len() {
+            self.ids.resize(key + 1, NO_CLASS);
+        }
+
+        if self.ids[key] == NO_CLASS {
+            self.ids[key] = self.classes.len() as u32;
+            self.classes.push(SegmentClass {
+                bbox: BoundingBox::from_point(x, y),
+                pixels: 0,
+                color: BACKGROUND_COLOR,
+            });
+        }
+
+        let idx = self.ids[key];
+        let class = &mut self.classes[idx as usize];
+        class.bbox.add(x, y);
+        class.pixels += 1;
+        self.pixel_classes[y * self.size.width + x] = idx;
+    }
+
+    /// Sorts the clusters with the largest first and assigns their class ids and colors. If
+    /// there are more clusters than class ids, the smallest ones stay background.
+    fn assign_classes(&mut self, palette: &mut Palette) {
+        let classes = &self.classes;
+        self.order.clear();
+        self.order.extend(0..classes.len());
+        self.order.sort_unstable_by_key(|&i| {
+            let class = &classes[i];
+            (Reverse(class.pixels), class.bbox.min, class.bbox.max)
+        });
+        self.order.truncate(u16::MAX as usize);
+
+        self.ranks.clear();
+        self.ranks.resize(classes.len(), BACKGROUND_CLASS_ID);
+        for (rank, &i) in self.order.iter().enumerate() {
+            self.ranks[i] = rank as u16 + 1;
+        }
+
+        // Only the classes in the annotation context need a color, the palette search is too
+        // slow for the thousands of clusters of a noisy frame
+        self.sorted.clear();
+        self.sorted.extend(
+            self.order
+                .iter()
+                .enumerate()
+                .map(|(rank, &i)| SegmentClass {
+                    color: if rank < MAX_LABELED_CLASSES {
+                        palette.color(classes[i].bbox)
+                    } else {
+                        BACKGROUND_COLOR
+                    },
+                    ..classes[i]
+                }),
+        );
+        std::mem::swap(&mut self.classes, &mut self.sorted);
+
+        for (class_id, &idx) in self.class_ids.iter_mut().zip(&self.pixel_classes) {
+            *class_id = if idx == NO_CLASS {
+                BACKGROUND_CLASS_ID
+            } else {
+                self.ranks[idx as usize]
+            };
+        }
+    }
+}
+
+/// Segments the pixels by their connected component, components smaller than
+/// `MIN_CLUSTER_PIXELS` are background.
+pub fn debug_connected_components(
+    dst: &mut Segmentation,
+    uf: &mut UnionFind,
+    palette: &mut Palette,
+) {
+    let width = dst.size.width;
+    dst.clear();
+
+    for i in 0..dst.class_ids.len() {
+        let representative = uf.get_representative(i);
         if uf.get_set_size(representative) < MIN_CLUSTER_PIXELS {
-            dst_slice[i..i + 3].copy_from_slice(&BLACK_COLOR);
             continue;
         }
 
-        // Generate a unique color based on the representative
-        let mut hasher = DefaultHasher::new();
-        representative.hash(&mut hasher);
-        let hash = hasher.finish();
-        let color = [
-            ((hash >> 16) & 0xFF) as u8,
-            ((hash >> 8) & 0xFF) as u8,
-            (hash & 0xFF) as u8,
-        ];
-
-        dst_slice[i..i + 3].copy_from_slice(&color);
+        dst.add(representative, i % width, i / width);
     }
+
+    dst.assign_classes(palette);
 }
 
+/// Segments the pixels by their gradient cluster. The clusters are in twice the resolution of
+/// the image, a pixel belongs to the first of the clusters it's part of.
 pub fn debug_gradient_clusters(
-    dst: &mut Image<u8, 3, CpuAllocator>,
+    dst: &mut Segmentation,
     clusters: &HashMap<(usize, usize), Vec<GradientInfo>>,
+    palette: &mut Palette,
 ) {
-    let dst_width = dst.width();
+    dst.clear();
 
-    // Make everything black
-    for px in dst.as_slice_mut() {
-        *px = 0;
-    }
-
-    let dst_slice = dst.as_slice_mut();
+    // The iteration order of the map changes between runs, sort the clusters by their key
+    let mut clusters = clusters.iter().collect::<Vec<_>>();
+    clusters.sort_unstable_by_key(|(key, _)| **key);
 
+    // Only the pixels a cluster gets count, not the ones taken by an earlier cluster or the
+    // same pixel again in the higher resolution
     for (i, (_, infos)) in clusters.iter().enumerate() {
-        let color = [
-            (((i + 1) * 37) % 256) as u8,
-            (((i + 1) * 59) % 256) as u8,
-            (((i + 1) * 83) % 256) as u8,
-        ];
+        for info in infos.iter() {
+            let (x, y) = (info.pos.x / 2, info.pos.y / 2);
 
-        for info in infos {
-            let idx = ((info.pos.y / 2) * dst_width + (info.pos.x / 2)) * 3;
-
-            if dst_slice[idx..idx + 3] == [0, 0, 0] {
-                dst_slice[idx..idx + 3].copy_from_slice(&color);
+            if !dst.is_assigned(x, y) {
+                dst.add(i, x, y);
             }
         }
     }
+
+    dst.assign_classes(palette);
 }
@@ -0,0 +1,196 @@
+use std::path::PathBuf;
+
+use kornia_image::{
+    Image, ImageSize,
+    allocator::{CpuAllocator, ImageAllocator},
+};
+use kornia_imgproc::color::gray_from_rgb_u8;
+use kornia_io::{
+    jpeg::read_image_jpeg_rgb8,
+    stream::{StreamCapture, V4L2CameraConfig},
+};
+
+/// Pixel format requested from the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb8,
+    /// Skips the color conversion of the pipeline, if the color of the frames isn't needed
+    Gray8,
+}
+
+/// Frame borrowed from the source, only valid while it's handled.
+pub enum FrameRef<'a, A: ImageAllocator> {
+    Rgb(&'a Image<u8, 3, A>),
+    Gray(&'a Image<u8, 1, A>),
+}
+
+impl<A: ImageAllocator> FrameRef<'_, A> {
+    pub fn size(&self) -> ImageSize {
+        match self {
+            FrameRef::Rgb(img) => img.size(),
+            FrameRef::Gray(img) => img.size(),
+        }
+    }
+}
+
+/// Consumes the frames of a [`FrameSource`]. The frames are handed over without copying them,
+/// so the handler must not keep them around.
+pub trait FrameHandler {
+    fn handle<A: ImageAllocator>(
+        &mut self,
+        frame: FrameRef<'_, A>,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Source of the frames fed into the pipeline. The camera is closed when the source is dropped,
+/// so it's released on every exit path, including errors and panics.
+pub enum FrameSource {
+    Camera {
+        capture: StreamCapture,
+        format: PixelFormat,
+        /// Set once the capture is closed, so it isn't closed twice
+        closed: bool,
+    },
+    /// Images decoded upfront and replayed in a loop, so the decoding isn't part of the frame
+    Images {
+        images: Vec<Image<u8, 3, CpuAllocator>>,
+        next: usize,
+    },
+    /// Same as `Images`, but converted to grayscale upfront
+    GrayImages {
+        images: Vec<Image<u8, 1, CpuAllocator>>,
+        next: usize,
+    },
+}
+
+impl FrameSource {
+    pub fn camera(
+        camera_id: u32,
+        fps: u32,
+        size: ImageSize,
+        format: PixelFormat,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut capture = match format {
+            PixelFormat::Rgb8 => V4L2CameraConfig::new()
+                .with_camera_id(camera_id)
+                .with_fps(fps)
+                .with_size(size)
+                .build()?,
+            // Let GStreamer convert to GRAY8, so the frames never exist in RGB
+            PixelFormat::Gray8 => StreamCapture::new(&format!(
+                "v4l2src device=/dev/video{camera_id} ! \
+                 video/x-raw,width={},height={},framerate={fps}/1 ! \
+                 videoconvert ! video/x-raw,format=GRAY8 ! \
+                 appsink name=sink emit-signals=true sync=false max-buffers=1 drop=true",
+                size.width, size.height
+            ))?,
+        };
+        capture.start()?;
+
+        Ok(Self::Camera {
+            capture,
+            format,
+            closed: false,
+        })
+    }
+
+    pub fn images(
+        paths: &[PathBuf],
+        format: PixelFormat,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let images = paths
+            .iter()
+            .map(read_image_jpeg_rgb8)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if images.is_empty() {
+            return Err("No images passed".into());
+        }
+
+        match format {
+            PixelFormat::Rgb8 => Ok(Self::Images { images, next: 0 }),
+            PixelFormat::Gray8 => {
+                let images = images
+                    .iter()
+                    .map(|img| {
+                        let mut gray = Image::from_size_val(img.size(), 0u8, CpuAllocator)?;
+                        gray_from_rgb_u8(img, &mut gray)?;
+                        Ok(gray)
+                    })
+                    .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+                Ok(Self::GrayImages { images, next: 0 })
+            }
+        }
+    }
+
+    /// Grabs the next frame and passes it to the handler, returns `false` if no frame is
+    /// available yet.
+    pub fn grab_with<H: FrameHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        match self {
+            // The GStreamer buffer is only borrowed by the handler and released before the next
+            // grab, which avoids the crash the frames used to be copied for
+            FrameSource::Camera {
+                capture,
+                format: PixelFormat::Rgb8,
+                ..
+            } => {
+                let Some(img) = capture.grab_rgb8()? else {
+                    return Ok(false);
+                };
+
+                handler.handle(FrameRef::Rgb(&img))?;
+            }
+            FrameSource::Camera {
+                capture,
+                format: PixelFormat::Gray8,
+                ..
+            } => {
+                let Some(img) = capture.grab_gray8()? else {
+                    return Ok(false);
+                };
+
+                handler.handle(FrameRef::Gray(&img))?;
+            }
+            FrameSource::Images { images, next } => {
+                handler.handle(FrameRef::Rgb(&images[*next]))?;
+                *next = (*next + 1) % images.len();
+            }
+            FrameSource::GrayImages { images, next } => {
+                handler.handle(FrameRef::Gray(&images[*next]))?;
+                *next = (*next + 1) % images.len();
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let FrameSource::Camera {
+            capture, closed, ..
+        } = self
+        {
+            if *closed {
+                return Ok(());
+            }
+
+            // Only try once, a failed close must not be retried when the source is dropped
+            *closed = true;
+            capture.close()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for FrameSource {
+    fn drop(&mut self) {
+        // NOTE: the app hangs on exit if the GStreamer pipeline is left running
+        if let Err(err) = self.close() {
+            eprintln!("Failed to close the camera: {err}");
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use device_supervisor::{Status, StatusLevel, Supervisor, SupervisorConfig, Transition};
+use kornia_image::allocator::ImageAllocator;
+
+use crate::source::{FrameHandler, FrameRef, FrameSource};
+
+/// Forwards the frames to the wrapped handler, keeping its errors apart from the capture errors.
+struct HandlerAdapter<'a, H> {
+    handler: &'a mut H,
+    error: Option<Box<dyn std::error::Error>>,
+}
+
+impl<H: FrameHandler> FrameHandler for HandlerAdapter<'_, H> {
+    fn handle<A: ImageAllocator>(
+        &mut self,
+        frame: FrameRef<'_, A>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Err(err) = self.handler.handle(frame) {
+            self.error = Some(err);
+        }
+
+        Ok(())
+    }
+}
+
+/// [`FrameSource`] which survives capture errors, it's closed and reopened as decided by the
+/// [`Supervisor`]. Every change of the status is logged as rerun text log.
+///
+/// Errors of the frame handler aren't capture errors, they are returned as is.
+pub struct SupervisedSource<F> {
+    open: F,
+    source: Option<FrameSource>,
+    supervisor: Supervisor,
+    rec: rerun::RecordingStream,
+    status_path: String,
+}
+
+impl<F> SupervisedSource<F>
+where
+    F: FnMut() -> Result<FrameSource, Box<dyn std::error::Error>>,
+{
+    /// The source is opened with `open` on the first grab, and again whenever it's lost.
+    pub fn new(
+        open: F,
+        config: SupervisorConfig,
+        rec: rerun::RecordingStream,
+        status_path: impl Into<String>,
+    ) -> Self {
+        Self {
+            open,
+            source: None,
+            supervisor: Supervisor::new(config),
+            rec,
+            status_path: status_path.into(),
+        }
+    }
+
+    /// Grabs the next frame and passes it to the handler, returns `false` if no frame is
+    /// available yet, including while the device is being reopened.
+    pub fn grab_with<H: FrameHandler>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(source) = self.source.as_mut() else {
+            self.reopen()?;
+            return Ok(false);
+        };
+
+        let mut adapter = HandlerAdapter {
+            handler,
+            error: None,
+        };
+
+        let result = source.grab_with(&mut adapter);
+        if let Some(err) = adapter.error {
+            return Err(err);
+        }
+
+        let (grabbed, transition) = match result {
+            Ok(true) => (true, self.supervisor.frame()),
+            Ok(false) => (false, self.supervisor.no_frame()),
+            Err(err) => (false, self.supervisor.grab_failed(err)),
+        };
+
+        match transition {
+            Transition::None => {}
+            Transition::Status(status) => self.log_status(status)?,
+            Transition::Lost(status) => {
+                self.log_status(status)?;
+
+                if let Some(Err(err)) = self.source.take().map(|mut source| source.close()) {
+                    self.log_status(Status {
+                        level: StatusLevel::Warn,
+                        message: format!("Failed to close: {err}"),
+                    })?;
+                }
+            }
+        }
+
+        Ok(grabbed)
+    }
+
+    pub fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(mut source) = self.source.take() {
+            source.close()?;
+            self.log_status(Status {
+                level: StatusLevel::Info,
+                message: "Closed".to_string(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Opens the source once the backoff has passed.
+    fn reopen(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let delay = self.supervisor.open_delay();
+        if !delay.is_zero() {
+            // Sleep in short steps, so the caller keeps checking its cancel token
+            std::thread::sleep(delay.min(Duration::from_millis(50)));
+            return Ok(());
+        }
+
+        let status = match (self.open)() {
+            Ok(source) => {
+                self.source = Some(source);
+                self.supervisor.opened()
+            }
+            Err(err) => self.supervisor.open_failed(err),
+        };
+
+        self.log_status(status)
+    }
+
+    fn log_status(&self, status: Status) -> Result<(), Box<dyn std::error::Error>> {
+        let level = match status.level {
+            StatusLevel::Info => rerun::TextLogLevel::INFO,
+            StatusLevel::Warn => rerun::TextLogLevel::WARN,
+            StatusLevel::Error => rerun::TextLogLevel::ERROR,
+        };
+
+        println!("{}: {}", self.status_path, status.message);
+        self.rec.log(
+            self.status_path.as_str(),
+            &rerun::TextLog::new(status.message).with_level(level),
+        )?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+use std::path::Path;
+
+use kornia_apriltag::{DecodeTagsConfig, family::TagFamilyKind};
+use kornia_image::{Image, ImageSize, allocator::CpuAllocator};
+use kornia_io::png::read_image_png_rgba8;
+use rand::Rng;
+
+use crate::{
+    family::{family_image_prefix, family_name},
+    homography::{homography_from_points, project},
+};
+
+/// Gray value of the background of the synthetic scenes.
+const BACKGROUND: f32 = 160.0;
+/// Number of samples per axis, used to anti-alias the edges of the tags.
+const SUPERSAMPLING: usize = 3;
+
+/// Tag image loaded from `apriltag_imgs`.
+pub struct TagImage {
+    pub family: TagFamilyKind,
+    pub id: usize,
+    /// Width of the image in bit cells, including the white border
+    pub total_width: usize,
+    /// Width of the black border in bit cells, the detected corners lie on it
+    pub width_at_border: usize,
+    /// Gray value of every bit cell, transparent cells are white
+    pub cells: Vec<u8>,
+}
+
+impl TagImage {
+    fn cell(&self, x: f32, y: f32) -> Option<f32> {
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+
+        let (x, y) = (x as usize, y as usize);
+        if x >= self.total_width || y >= self.total_width {
+            return None;
+        }
+
+        Some(self.cells[y * self.total_width + x] as f32)
+    }
+}
+
+/// Loads the first `max_tags` tag images of the family from the `apriltag_imgs` directory.
+pub fn load_tag_images(
+    root: impl AsRef<Path>,
+    family: TagFamilyKind,
+    max_tags: usize,
+) -> Result<Vec<TagImage>, Box<dyn std::error::Error>> {
+    let name = family_name(&family);
+    let prefix = family_image_prefix(&family).ok_or("Tag family isn't part of apriltag_imgs")?;
+    let tag_family = DecodeTagsConfig::new(vec![family.clone()])
+        .tag_families
+        .swap_remove(0);
+
+    let mut tags = Vec::new();
+
+    for id in 0..max_tags {
+        let path = root
+            .as_ref()
+            .join(name)
+            .join(format!("{prefix}_{id:05}.png"));
+        if !path.exists() {
+            break;
+        }
+
+        let img = read_image_png_rgba8(&path)?;
+        let cells = img
+            .as_slice()
+            .chunks_exact(4)
+            .map(|px| if px[3] == 0 { 255 } else { px[0] })
+            .collect();
+
+        tags.push(TagImage {
+            family: family.clone(),
+            id,
+            total_width: img.width(),
+            width_at_border: tag_family.width_at_border,
+            cells,
+        });
+    }
+
+    if tags.is_empty() {
+        return Err(format!(
+            "No tag images found for {name} in {}, did you initialize the submodule?",
+            root.as_ref().display()
+        )
+        .into());
+    }
+
+    Ok(tags)
+}
+
+/// Distortions applied to a synthetic scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Distortion {
+    /// Width of the tag, including the white border, in pixels
+    pub tag_size: f32,
+    /// In-plane rotation of the tag in radians
+    pub rotation: f32,
+    /// Maximum random displacement of every corner, relative to the tag size
+    pub perspective: f32,
+    /// Sigma of the gaussian blur in pixels, `0` disables the blur
+    pub blur_sigma: f32,
+    /// Sigma of the additive gaussian noise in gray levels
+    pub noise_sigma: f32,
+    /// Strength of the linear lighting gradient across the image, between `0` and `1`
+    pub lighting: f32,
+}
+
+impl Default for Distortion {
+    fn default() -> Self {
+        Self {
+            tag_size: 120.0,
+            rotation: 0.0,
+            perspective: 0.0,
+            blur_sigma: 0.0,
+            noise_sigma: 0.0,
+            lighting: 0.0,
+        }
+    }
+}
+
+/// Ground truth of a tag placed in a synthetic scene.
+#[derive(Debug, Clone, Copy)]
+pub struct PlacedTag {
+    pub id: usize,
+    /// Outer corners of the black border in image coordinates
+    pub corners: [[f32; 2]; 4],
+    pub center: [f32; 2],
+}
+
+pub struct Scene {
+    pub image: Image<u8, 1, CpuAllocator>,
+    pub tags: Vec<PlacedTag>,
+}
+
+/// Renders the tags into a scene of the given size. Tags are placed on a grid so they don't
+/// overlap, every tag receives the same distortion with a random position jitter.
+pub fn render_scene<R: Rng>(
+    size: ImageSize,
+    tags: &[&TagImage],
+    distortion: &Distortion,
+    rng: &mut R,
+) -> Result<Scene, Box<dyn std::error::Error>> {
+    let mut pixels = vec![BACKGROUND; size.width * size.height];
+    let mut placed = Vec::with_capacity(tags.len());
+
+    let columns = (tags.len() as f32).sqrt().ceil().max(1.0) as usize;
+    let rows = tags.len().div_ceil(columns).max(1);
+    let cell_width = size.width as f32 / columns as f32;
+    let cell_height = size.height as f32 / rows as f32;
+
+    for (i, tag) in tags.iter().enumerate() {
+        let half = distortion.tag_size / 2.0;
+        let slack_x = (cell_width / 2.0 - half * std::f32::consts::SQRT_2).max(0.0);
+        let slack_y = (cell_height / 2.0 - half * std::f32::consts::SQRT_2).max(0.0);
+
+        let center = [
+            ((i % columns) as f32 + 0.5) * cell_width + rng.random_range(-1.0..=1.0) * slack_x,
+            ((i / columns) as f32 + 0.5) * cell_height + rng.random_range(-1.0..=1.0) * slack_y,
+        ];
+
+        let (sin, cos) = distortion.rotation.sin_cos();
+        let max_offset = distortion.perspective * distortion.tag_size;
+
+        // Corners of the tag image, clockwise from top left
+        let dst = [[-half, -half], [half, -half], [half, half], [-half, half]].map(|[x, y]| {
+            [
+                (center[0] + x * cos - y * sin + rng.random_range(-1.0..=1.0) * max_offset) as f64,
+                (center[1] + x * sin + y * cos + rng.random_range(-1.0..=1.0) * max_offset) as f64,
+            ]
+        });
+
+        let total_width = tag.total_width as f64;
+        let src = [
+            [0.0, 0.0],
+            [total_width, 0.0],
+            [total_width, total_width],
+            [0.0, total_width],
+        ];
+
+        let to_image = homography_from_points(&src, &dst).ok_or("Degenerate tag placement")?;
+        let to_tag = homography_from_points(&dst, &src).ok_or("Degenerate tag placement")?;
+
+        draw_tag(&mut pixels, size, tag, &dst, &to_tag);
+
+        let border = (tag.total_width - tag.width_at_border) as f32 / 2.0;
+        let far = border + tag.width_at_border as f32;
+        let corners = [[border, border], [far, border], [far, far], [border, far]]
+            .map(|[x, y]| project(&to_image, x, y));
+        let tag_center = tag.total_width as f32 / 2.0;
+
+        placed.push(PlacedTag {
+            id: tag.id,
+            corners,
+            center: project(&to_image, tag_center, tag_center),
+        });
+    }
+
+    apply_lighting(&mut pixels, size, distortion.lighting, rng);
+
+    if distortion.blur_sigma > 0.0 {
+        gaussian_blur(&mut pixels, size, distortion.blur_sigma);
+    }
+
+    if distortion.noise_sigma > 0.0 {
+        for px in pixels.iter_mut() {
+            *px += gaussian(rng) * distortion.noise_sigma;
+        }
+    }
+
+    let data = pixels
+        .into_iter()
+        .map(|px| px.round().clamp(0.0, 255.0) as u8)
+        .collect();
+
+    Ok(Scene {
+        image: Image::new(size, data, CpuAllocator)?,
+        tags: placed,
+    })
+}
+
+/// Draws the tag by mapping every pixel inside its bounding box back onto the tag image.
+fn draw_tag(
+    pixels: &mut [f32],
+    size: ImageSize,
+    tag: &TagImage,
+    corners: &[[f64; 2]; 4],
+    to_tag: &[f32; 9],
+) {
+    let min_x = corners
+        .iter()
+        .map(|c| c[0])
+        .fold(f64::MAX, f64::min)
+        .floor()
+        .max(0.0) as usize;
+    let min_y = corners
+        .iter()
+        .map(|c| c[1])
+        .fold(f64::MAX, f64::min)
+        .floor()
+        .max(0.0) as usize;
+    let max_x = (corners.iter().map(|c| c[0]).fold(f64::MIN, f64::max).ceil() as usize)
+        .min(size.width.saturating_sub(1));
+    let max_y = (corners.iter().map(|c| c[1]).fold(f64::MIN, f64::max).ceil() as usize)
+        .min(size.height.saturating_sub(1));
+
+    let step = 1.0 / SUPERSAMPLING as f32;
+
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let mut sum = 0.0;
+            let mut hits = 0;
+
+            for sy in 0..SUPERSAMPLING {
+                for sx in 0..SUPERSAMPLING {
+                    let [tx, ty] = project(
+                        to_tag,
+                        x as f32 + (sx as f32 + 0.5) * step,
+                        y as f32 + (sy as f32 + 0.5) * step,
+                    );
+
+                    if let Some(value) = tag.cell(tx, ty) {
+                        sum += value;
+                        hits += 1;
+                    }
+                }
+            }
+
+            if hits > 0 {
+                let px = &mut pixels[y * size.width + x];
+                let coverage = hits as f32 / (SUPERSAMPLING * SUPERSAMPLING) as f32;
+                *px = *px * (1.0 - coverage) + sum / hits as f32 * coverage;
+            }
+        }
+    }
+}
+
+/// Darkens the image with a linear gradient in a random direction.
+fn apply_lighting<R: Rng>(pixels: &mut [f32], size: ImageSize, strength: f32, rng: &mut R) {
+    if strength <= 0.0 {
+        return;
+    }
+
+    let angle = rng.random_range(0.0..std::f32::consts::TAU);
+    let (dy, dx) = angle.sin_cos();
+    let half_extent = (size.width as f32 * dx.abs() + size.height as f32 * dy.abs()) / 2.0;
+
+    for y in 0..size.height {
+        for x in 0..size.width {
+            let along = (x as f32 - size.width as f32 / 2.0) * dx
+                + (y as f32 - size.height as f32 / 2.0) * dy;
+            // Goes from 0 to 1 along the gradient direction
+            let t = (along / half_extent + 1.0) / 2.0;
+
+            pixels[y * size.width + x] *= 1.0 - strength * t;
+        }
+    }
+}
+
+/// Separable gaussian blur.
+fn gaussian_blur(pixels: &mut [f32], size: ImageSize, sigma: f32) {
+    let radius = (3.0 * sigma).ceil() as isize;
+    let mut kernel = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let sum = kernel.iter().sum::<f32>();
+    kernel.iter_mut().for_each(|k| *k /= sum);
+
+    let mut tmp = vec![0.0; pixels.len()];
+    let (width, height) = (size.width as isize, size.height as isize);
+
+    for y in 0..height {
+        for x in 0..width {
+            tmp[(y * width + x) as usize] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| {
+                    let sx = (x + k as isize - radius).clamp(0, width - 1);
+                    pixels[(y * width + sx) as usize] * weight
+                })
+                .sum();
+        }
+    }
+
+    for y in 0..height {
+        for x in 0..width {
+            pixels[(y * width + x) as usize] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| {
+                    let sy = (y + k as isize - radius).clamp(0, height - 1);
+                    tmp[(sy * width + x) as usize] * weight
+                })
+                .sum();
+        }
+    }
+}
+
+/// Samples the standard normal distribution using the Box-Muller transform.
+fn gaussian<R: Rng>(rng: &mut R) -> f32 {
+    let u1 = rng.random_range(f32::EPSILON..1.0);
+    let u2 = rng.random_range(0.0..1.0);
+
+    (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
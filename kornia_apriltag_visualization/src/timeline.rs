@@ -0,0 +1,18 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Sequence timeline holding the index of the processed frame.
+pub const FRAME_TIMELINE: &str = "frame";
+/// Timestamp timeline holding the wall-clock time at which the frame was captured.
+pub const CAPTURE_TIMELINE: &str = "capture_time";
+
+/// Sets both the frame index and capture timestamp timelines, so every entity logged afterwards
+/// belongs to this frame.
+pub fn set_frame_time(rec: &rerun::RecordingStream, frame_idx: u64, capture_time: SystemTime) {
+    rec.set_time_sequence(FRAME_TIMELINE, frame_idx as i64);
+
+    let secs_since_epoch = capture_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    rec.set_timestamp_secs_since_epoch(CAPTURE_TIMELINE, secs_since_epoch);
+}
@@ -0,0 +1,377 @@
+use kornia_apriltag::decoder::Detection;
+
+use crate::{detection::tag_color, family::family_name, roi::Roi};
+
+/// Parameters of the [`Tracker`].
+#[derive(Debug, Clone, Copy)]
+pub struct TrackerConfig {
+    /// Maximum distance in pixels between the predicted and detected center of a tag to be
+    /// associated with the same track
+    pub max_distance: f32,
+    /// Number of consecutive frames a track survives without a detection
+    pub max_misses: u32,
+    /// Acceleration noise of the constant velocity model in pixels/s²
+    pub process_noise: f32,
+    /// Standard deviation of the detected corners in pixels
+    pub measurement_noise: f32,
+    /// Margin in pixels around the predicted corners of a tag, see [`Tracker::predicted_rois`]
+    pub roi_margin: f32,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            max_distance: 50.0,
+            max_misses: 10,
+            process_noise: 500.0,
+            measurement_noise: 1.0,
+            roi_margin: 20.0,
+        }
+    }
+}
+
+/// Kalman filter of a single coordinate with a constant velocity model.
+#[derive(Debug, Clone, Copy)]
+struct ConstantVelocity {
+    position: f32,
+    velocity: f32,
+    covariance: [[f32; 2]; 2],
+}
+
+impl ConstantVelocity {
+    fn new(position: f32, config: &TrackerConfig) -> Self {
+        let variance = config.measurement_noise * config.measurement_noise;
+
+        Self {
+            position,
+            velocity: 0.0,
+            // The velocity of a new track is unknown
+            covariance: [
+                [variance, 0.0],
+                [0.0, config.max_distance * config.max_distance],
+            ],
+        }
+    }
+
+    fn predict(&mut self, dt: f32, config: &TrackerConfig) {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let q = config.process_noise * config.process_noise;
+
+        self.position += self.velocity * dt;
+        self.covariance = [
+            [
+                p00 + dt * (p01 + p10) + dt * dt * p11 + q * dt.powi(3) / 3.0,
+                p01 + dt * p11 + q * dt * dt / 2.0,
+            ],
+            [p10 + dt * p11 + q * dt * dt / 2.0, p11 + q * dt],
+        ];
+    }
+
+    fn update(&mut self, measurement: f32, config: &TrackerConfig) {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let s = p00 + config.measurement_noise * config.measurement_noise;
+        let k0 = p00 / s;
+        let k1 = p10 / s;
+        let innovation = measurement - self.position;
+
+        self.position += k0 * innovation;
+        self.velocity += k1 * innovation;
+        self.covariance = [
+            [(1.0 - k0) * p00, (1.0 - k0) * p01],
+            [p10 - k1 * p00, p11 - k1 * p01],
+        ];
+    }
+}
+
+/// Tag followed across frames.
+#[derive(Debug, Clone)]
+pub struct Track {
+    /// Unique id of the track, independent of the tag id
+    pub track_id: u64,
+    pub family: &'static str,
+    pub tag_id: u16,
+    /// Frame index of the first detection
+    pub first_seen: u64,
+    /// Frame index of the last detection
+    pub last_seen: u64,
+    /// Number of frames the tag was detected in
+    pub hits: u64,
+    /// Consecutive frames without a detection
+    pub misses: u32,
+    /// Number of times the tag wasn't detected for at least one frame and then found again
+    pub dropouts: u64,
+    /// Filters of the x and y coordinates of the 4 corners
+    filters: [[ConstantVelocity; 2]; 4],
+}
+
+impl Track {
+    /// Filtered corners of the tag.
+    pub fn corners(&self) -> [[f32; 2]; 4] {
+        self.filters.map(|[x, y]| [x.position, y.position])
+    }
+
+    pub fn center(&self) -> [f32; 2] {
+        let corners = self.corners();
+        [
+            corners.iter().map(|c| c[0]).sum::<f32>() / 4.0,
+            corners.iter().map(|c| c[1]).sum::<f32>() / 4.0,
+        ]
+    }
+
+    /// Corners extrapolated `dt` seconds into the future.
+    pub fn predicted_corners(&self, dt: f32) -> [[f32; 2]; 4] {
+        self.filters
+            .map(|[x, y]| [x.position + x.velocity * dt, y.position + y.velocity * dt])
+    }
+
+    /// Number of frames since the first detection.
+    pub fn lifetime(&self, frame_idx: u64) -> u64 {
+        frame_idx - self.first_seen + 1
+    }
+}
+
+/// Event of a track, logged as text so the lifetime of every track can be followed.
+#[derive(Debug, Clone)]
+pub enum TrackEvent {
+    Started(Track),
+    Recovered(Track),
+    Lost(Track),
+}
+
+/// Associates the detections of consecutive frames by tag id and position, and smooths their
+/// corners with a constant velocity Kalman filter.
+pub struct Tracker {
+    pub config: TrackerConfig,
+    pub tracks: Vec<Track>,
+    next_track_id: u64,
+}
+
+impl Tracker {
+    pub fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            tracks: Vec::new(),
+            next_track_id: 0,
+        }
+    }
+
+    /// Predicts every track `dt` seconds forward and updates them with the detections of the
+    /// frame. Returns the tracks which were started, recovered or lost in this frame.
+    pub fn update(&mut self, frame_idx: u64, dt: f32, detections: &[Detection]) -> Vec<TrackEvent> {
+        let config = self.config;
+        let mut events = Vec::new();
+
+        for track in &mut self.tracks {
+            for filter in track.filters.iter_mut().flatten() {
+                filter.predict(dt, &config);
+            }
+        }
+
+        let mut matched = vec![false; self.tracks.len()];
+
+        for tag in detections {
+            let family = family_name(&tag.tag_family_kind);
+            let center = [tag.center.x, tag.center.y];
+
+            // Closest unmatched track of the same tag
+            let closest = self
+                .tracks
+                .iter()
+                .enumerate()
+                .filter(|(i, track)| {
+                    !matched[*i] && track.family == family && track.tag_id == tag.id
+                })
+                .map(|(i, track)| {
+                    let [x, y] = track.center();
+                    (i, (x - center[0]).hypot(y - center[1]))
+                })
+                .filter(|(_, distance)| *distance <= config.max_distance)
+                .min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+            match closest {
+                Some((i, _)) => {
+                    matched[i] = true;
+
+                    let track = &mut self.tracks[i];
+                    for (filters, corner) in track.filters.iter_mut().zip(&tag.quad.corners) {
+                        filters[0].update(corner.x, &config);
+                        filters[1].update(corner.y, &config);
+                    }
+
+                    if track.misses > 0 {
+                        track.dropouts += 1;
+                        events.push(TrackEvent::Recovered(track.clone()));
+                    }
+
+                    track.last_seen = frame_idx;
+                    track.hits += 1;
+                    track.misses = 0;
+                }
+                None => {
+                    let track = Track {
+                        track_id: self.next_track_id,
+                        family,
+                        tag_id: tag.id,
+                        first_seen: frame_idx,
+                        last_seen: frame_idx,
+                        hits: 1,
+                        misses: 0,
+                        dropouts: 0,
+                        filters: tag.quad.corners.map(|corner| {
+                            [
+                                ConstantVelocity::new(corner.x, &config),
+                                ConstantVelocity::new(corner.y, &config),
+                            ]
+                        }),
+                    };
+                    self.next_track_id += 1;
+
+                    events.push(TrackEvent::Started(track.clone()));
+                    self.tracks.push(track);
+                    matched.push(true);
+                }
+            }
+        }
+
+        for (track, matched) in self.tracks.iter_mut().zip(matched) {
+            if !matched {
+                track.misses += 1;
+            }
+        }
+
+        self.tracks.retain(|track| {
+            let alive = track.misses <= config.max_misses;
+            if !alive {
+                events.push(TrackEvent::Lost(track.clone()));
+            }
+            alive
+        });
+
+        events
+    }
+
+    /// Regions where the tracked tags are expected `dt` seconds from now, grown by the ROI margin
+    /// on every side.
+    pub fn predicted_rois(&self, dt: f32) -> Vec<Roi> {
+        let margin = self.config.roi_margin;
+
+        self.tracks
+            .iter()
+            .map(|track| {
+                let corners = track.predicted_corners(dt);
+                let mut roi = Roi {
+                    min: [f32::MAX; 2],
+                    max: [f32::MIN; 2],
+                };
+
+                for [x, y] in corners {
+                    roi.min = [roi.min[0].min(x - margin), roi.min[1].min(y - margin)];
+                    roi.max = [roi.max[0].max(x + margin), roi.max[1].max(y + margin)];
+                }
+
+                roi
+            })
+            .collect()
+    }
+}
+
+/// Logs the filtered tracks and the predicted ROIs under `path`, and the track events and
+/// statistics under `stats_path`.
+pub fn log_tracks(
+    rec: &rerun::RecordingStream,
+    path: &str,
+    stats_path: &str,
+    tracker: &Tracker,
+    events: &[TrackEvent],
+    frame_idx: u64,
+    dt: f32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let strips = tracker.tracks.iter().map(|track| {
+        let mut points = track.corners().to_vec();
+        points.push(points[0]);
+        points
+    });
+    let colors = tracker.tracks.iter().map(|track| {
+        let [r, g, b] = tag_color(track.family, track.tag_id);
+        rerun::Color::from_rgb(r, g, b)
+    });
+    let labels = tracker.tracks.iter().map(|track| {
+        format!(
+            "track {} ({}:{}) age {}{}",
+            track.track_id,
+            track.family,
+            track.tag_id,
+            track.lifetime(frame_idx),
+            if track.misses > 0 { " (predicted)" } else { "" }
+        )
+    });
+
+    rec.log(
+        format!("{path}/tracks"),
+        &rerun::LineStrips2D::new(strips)
+            .with_colors(colors)
+            .with_labels(labels),
+    )?;
+
+    let rois = tracker.predicted_rois(dt);
+    rec.log(
+        format!("{path}/predicted_rois"),
+        &rerun::Boxes2D::from_mins_and_sizes(
+            rois.iter().map(|roi| roi.min),
+            rois.iter()
+                .map(|roi| [roi.max[0] - roi.min[0], roi.max[1] - roi.min[1]]),
+        )
+        .with_colors([rerun::Color::from_rgb(255, 255, 255)]),
+    )?;
+
+    for event in events {
+        let (level, message) = match event {
+            TrackEvent::Started(track) => (
+                rerun::TextLogLevel::INFO,
+                format!(
+                    "Started track {} for {}:{}",
+                    track.track_id, track.family, track.tag_id
+                ),
+            ),
+            TrackEvent::Recovered(track) => (
+                rerun::TextLogLevel::DEBUG,
+                format!(
+                    "Recovered track {} for {}:{} after a dropout, {} dropouts so far",
+                    track.track_id, track.family, track.tag_id, track.dropouts
+                ),
+            ),
+            TrackEvent::Lost(track) => (
+                rerun::TextLogLevel::WARN,
+                format!(
+                    "Lost track {} for {}:{} after {} frames with {} detections and {} dropouts",
+                    track.track_id,
+                    track.family,
+                    track.tag_id,
+                    track.last_seen - track.first_seen + 1,
+                    track.hits,
+                    track.dropouts
+                ),
+            ),
+        };
+
+        rec.log(
+            format!("{stats_path}/events"),
+            &rerun::TextLog::new(message).with_level(level),
+        )?;
+    }
+
+    rec.log(
+        format!("{stats_path}/active_tracks"),
+        &rerun::Scalars::new([tracker.tracks.len() as f64]),
+    )?;
+    rec.log(
+        format!("{stats_path}/coasting_tracks"),
+        &rerun::Scalars::new([tracker
+            .tracks
+            .iter()
+            .filter(|track| track.misses > 0)
+            .count() as f64]),
+    )?;
+
+    Ok(())
+}
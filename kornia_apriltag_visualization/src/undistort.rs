@@ -0,0 +1,112 @@
+use std::str::FromStr;
+
+use kornia_image::{
+    Image, ImageSize,
+    allocator::{CpuAllocator, ImageAllocator},
+};
+
+use crate::calibration::CameraCalibration;
+
+/// What gets undistorted before the pose is estimated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndistortMode {
+    /// Remap every frame before it's fed into the pipeline
+    Image,
+    /// Detect on the raw frame and only undistort the corners of the detected tags
+    Corners,
+}
+
+impl FromStr for UndistortMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "image" => Ok(Self::Image),
+            "corners" => Ok(Self::Corners),
+            _ => Err(format!(
+                "Unknown undistort mode {s}, expected image or corners"
+            )),
+        }
+    }
+}
+
+/// Precomputed remap tables from the undistorted image to the raw image, so undistorting a frame
+/// is a single lookup and bilinear interpolation per pixel.
+pub struct Undistorter {
+    size: ImageSize,
+    /// Position of every undistorted pixel in the raw image, row major
+    map: Vec<[f32; 2]>,
+}
+
+impl Undistorter {
+    /// Builds the remap tables for frames of `size`. The undistorted image keeps the intrinsics of
+    /// the calibration.
+    pub fn new(calibration: &CameraCalibration, size: ImageSize) -> Self {
+        let mut map = Vec::with_capacity(size.width * size.height);
+
+        for v in 0..size.height {
+            for u in 0..size.width {
+                let x = (u as f64 - calibration.cx) / calibration.fx;
+                let y = (v as f64 - calibration.cy) / calibration.fy;
+                let [xd, yd] = calibration.distort([x, y]);
+
+                map.push([
+                    (calibration.fx * xd + calibration.cx) as f32,
+                    (calibration.fy * yd + calibration.cy) as f32,
+                ]);
+            }
+        }
+
+        Self { size, map }
+    }
+
+    /// Remaps `src` into `dst`, pixels which fall outside of `src` are set to `0`.
+    pub fn undistort_image<A: ImageAllocator, const C: usize>(
+        &self,
+        src: &Image<u8, C, A>,
+        dst: &mut Image<u8, C, CpuAllocator>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if src.size() != self.size || dst.size() != self.size {
+            return Err(format!(
+                "Expected images of {}x{}, got {}x{} and {}x{}",
+                self.size.width,
+                self.size.height,
+                src.width(),
+                src.height(),
+                dst.width(),
+                dst.height()
+            )
+            .into());
+        }
+
+        let width = self.size.width;
+        let max_x = (width - 1) as f32;
+        let max_y = (self.size.height - 1) as f32;
+        let src_data = src.as_slice();
+
+        for (dst_px, &[x, y]) in dst.as_slice_mut().chunks_exact_mut(C).zip(&self.map) {
+            if !(0.0..=max_x).contains(&x) || !(0.0..=max_y).contains(&y) {
+                dst_px.fill(0);
+                continue;
+            }
+
+            let x0 = x as usize;
+            let y0 = y as usize;
+            let x1 = (x0 + 1).min(width - 1);
+            let y1 = (y0 + 1).min(self.size.height - 1);
+            let fx = x - x0 as f32;
+            let fy = y - y0 as f32;
+
+            for (c, value) in dst_px.iter_mut().enumerate() {
+                let at = |x: usize, y: usize| src_data[(y * width + x) * C + c] as f32;
+
+                let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+                let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+
+                *value = (top * (1.0 - fy) + bottom * fy).round() as u8;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::{path::Path, time::Instant};
+
+use kornia_image::{Image, ImageSize, allocator::CpuAllocator};
+use kornia_io::stream::video::{ImageFormat, VideoCodec, VideoWriter};
+
+/// H.264 video of RGB frames, e.g. the annotated frames of the [`crate::annotate::Renderer`].
+/// The video is finalized when the writer is closed or dropped, otherwise it can't be played.
+///
+/// The frames are placed by their capture time on the fixed frame rate of the video, so it plays
+/// in real time even when the capture rate differs or frames were dropped: a frame is repeated
+/// to fill the gap before it, and a frame captured before its slot in the video is skipped.
+pub struct AnnotatedVideoWriter {
+    writer: VideoWriter,
+    size: ImageSize,
+    fps: u32,
+    /// Capture time of the first frame, the start of the video
+    start: Option<Instant>,
+    /// Number of frames written into the video so far
+    written: u64,
+    closed: bool,
+}
+
+impl AnnotatedVideoWriter {
+    pub fn create(
+        path: impl AsRef<Path>,
+        fps: u32,
+        size: ImageSize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut writer =
+            VideoWriter::new(path, VideoCodec::H264, ImageFormat::Rgb8, fps as i32, size)?;
+        writer.start()?;
+
+        Ok(Self {
+            writer,
+            size,
+            fps,
+            start: None,
+            written: 0,
+            closed: false,
+        })
+    }
+
+    /// Writes the frame captured at `captured_at`, as often as needed to reach its capture time.
+    pub fn write(
+        &mut self,
+        img: &Image<u8, 3, CpuAllocator>,
+        captured_at: Instant,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if img.size() != self.size {
+            return Err(format!(
+                "Frame of {}x{} doesn't fit into the video of {}x{}",
+                img.width(),
+                img.height(),
+                self.size.width,
+                self.size.height
+            )
+            .into());
+        }
+
+        // Slot of the frame in the video, counted from the capture of the first frame
+        let start = *self.start.get_or_insert(captured_at);
+        let slot = (captured_at.saturating_duration_since(start).as_secs_f64() * self.fps as f64)
+            .round() as u64;
+
+        while self.written <= slot {
+            self.writer.write(img)?;
+            self.written += 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.closed {
+            return Ok(());
+        }
+
+        // Only try once, a failed close must not be retried when the writer is dropped
+        self.closed = true;
+        self.writer.close()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for AnnotatedVideoWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            eprintln!("Failed to close the video: {err}");
+        }
+    }
+}